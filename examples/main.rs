@@ -1,20 +1,14 @@
 extern crate include_merkle;
-use std::fs::File;
-use std::io::{BufWriter, Write};
 use std::path::Path;
 
 fn write_output(identity: &str, data: &[u8]) {
-    // Make sure the output directory exists
-    let output_dir = Path::new("./output");
-    std::fs::create_dir_all(output_dir).expect("failed to create output directory");
+    let store = include_merkle::ContentStore::new(Path::new("./output"), Default::default());
 
     // These should match
-    assert_eq!(include_merkle::compute_identity(&data), identity);
+    let stored_identity = store.put(data).expect("failed to write output file");
+    assert_eq!(stored_identity, identity);
 
-    let path = output_dir.join(&identity);
-    let file = File::create(&path).expect("failed to create output file");
-    let mut writer = BufWriter::new(file);
-    writer.write_all(data).expect("failed to write output file");
+    let path = store.path(identity).expect("invalid identity");
     println!("Wrote data out to {:?}", path);
 }
 
@@ -35,7 +29,7 @@ fn main() {
         let mut entry_graph = include_merkle::IncludeNodeGraph::new();
         let entry_node = include_merkle::traverse_build(
             &mut entry_graph,
-            &working_dir,
+            working_dir,
             &entry_path,
             0,
             normalize_endings,
@@ -44,8 +38,8 @@ fn main() {
         include_merkle::traverse_flatten(&mut entry_graph, entry_node, normalize_endings);
         let flattened_text = &entry_graph[entry_node].node.flattened;
         let flattened_data = flattened_text.as_bytes();
-        let flattened_identity = include_merkle::compute_identity(&flattened_data);
-        write_output(&flattened_identity, &flattened_data);
+        let flattened_identity = include_merkle::compute_identity(flattened_data);
+        write_output(&flattened_identity, flattened_data);
     }
 
     println!("---\nPatching Test\n*");
@@ -54,7 +48,7 @@ fn main() {
         let mut entry_graph = include_merkle::IncludeNodeGraph::new();
         let entry_node = include_merkle::traverse_build(
             &mut entry_graph,
-            &working_dir,
+            working_dir,
             &entry_path,
             0,
             normalize_endings,
@@ -64,13 +58,13 @@ fn main() {
             .iter()
             .for_each(|node| {
                 let patched_data = node.flattened.as_bytes();
-                let patched_identity = include_merkle::compute_identity(&patched_data);
-                write_output(&patched_identity, &patched_data);
+                let patched_identity = include_merkle::compute_identity(patched_data);
+                write_output(&patched_identity, patched_data);
             });
 
         if let Some(ref root_node) = include_merkle::get_root_node(&entry_graph) {
             let patched_identity = match root_node.patched_identity {
-                Some(ref identity) => &identity,
+                Some(ref identity) => identity,
                 None => "INVALID",
             };
             println!("Patched identity of root node: {}", patched_identity);
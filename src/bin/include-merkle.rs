@@ -0,0 +1,412 @@
+//! Command-line interface, enabled with the `cli` feature.
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use include_merkle::*;
+use petgraph::graph::NodeIndex;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Generate Merkle identities for files with include directives, and patch or flatten them.
+#[derive(Parser)]
+#[command(name = "include-merkle", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the patched identity of a root file
+    Hash {
+        #[command(flatten)]
+        input: InputArgs,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
+    },
+
+    /// Write a root file with every include directive replaced by the included file
+    Flatten {
+        #[command(flatten)]
+        input: InputArgs,
+
+        /// Output file (standard output if not set)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Embed files with `#pragma once` or an include guard only once
+        #[arg(long)]
+        include_once: bool,
+
+        /// Style of the `#line` directives emitted around embedded files
+        #[arg(long, value_enum, default_value_t = LineDirectives::None)]
+        line_directives: LineDirectives,
+    },
+
+    /// Write every patched file into a content store (see `ContentStore`), named like the
+    /// patched include directives, so the output directory is an include root for them
+    Patch {
+        #[command(flatten)]
+        input: InputArgs,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
+
+        /// Output directory
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Store files in subdirectories, to keep directories small; the output can then only be
+        /// read by identity, rather than used as an include root
+        #[arg(long)]
+        sharded: bool,
+
+        /// Text written before each identity in patched include paths and file names
+        #[arg(long, default_value = "")]
+        identity_prefix: String,
+
+        /// Text written after each identity in patched include paths and file names
+        #[arg(long, default_value = "")]
+        identity_suffix: String,
+    },
+
+    /// Print the include graph of a root file
+    Graph {
+        #[command(flatten)]
+        input: InputArgs,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = GraphFormat::Tree)]
+        format: GraphFormat,
+    },
+
+    /// List every file a root file depends on, starting with the root
+    Deps {
+        #[command(flatten)]
+        input: InputArgs,
+
+        /// Write a Make-style depfile for this target instead of a list
+        #[arg(long, value_name = "TARGET")]
+        make_target: Option<PathBuf>,
+
+        /// Add an empty rule for every included file to the depfile (like `gcc -MP`)
+        #[arg(long, requires = "make_target")]
+        phony_targets: bool,
+    },
+}
+
+/// Options for building the include graph of a root file.
+#[derive(Args)]
+struct InputArgs {
+    /// Root file
+    file: PathBuf,
+
+    /// Working directory, searched first for includes (defaults to the directory of the root)
+    #[arg(short, long)]
+    working_dir: Option<PathBuf>,
+
+    /// Additional include search path, tried in order after the working directory
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+
+    /// Predefined macro; enables conditional-compilation aware discovery
+    #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]")]
+    defines: Vec<String>,
+
+    /// Normalize line endings to Unix LF when loading files
+    #[arg(long)]
+    normalize_endings: bool,
+
+    /// Fail on include directives that can't be resolved
+    #[arg(long)]
+    strict: bool,
+}
+
+/// Options for computing identities.
+#[derive(Args)]
+struct IdentityArgs {
+    /// Hash algorithm of identities
+    #[arg(long, value_enum, default_value_t = Algorithm::Sha256)]
+    hash: Algorithm,
+
+    /// Text encoding of identities
+    #[arg(long, value_enum, default_value_t = Encoding::Base58)]
+    encoding: Encoding,
+
+    /// Write identities as multibase-encoded multihashes
+    #[arg(long)]
+    self_describing: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Algorithm {
+    Sha256,
+    Blake3,
+    Xxh3,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Encoding {
+    Base58,
+    Hex,
+    Base32,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LineDirectives {
+    None,
+    C,
+    Glsl,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum GraphFormat {
+    Tree,
+    Dot,
+    Json,
+}
+
+impl IdentityArgs {
+    fn format(&self) -> IdentityFormat {
+        IdentityFormat {
+            algorithm: match self.hash {
+                Algorithm::Sha256 => HashAlgorithm::Sha256,
+                Algorithm::Blake3 => HashAlgorithm::Blake3,
+                Algorithm::Xxh3 => HashAlgorithm::Xxh3,
+            },
+            encoding: match self.encoding {
+                Encoding::Base58 => IdentityEncoding::Base58,
+                Encoding::Hex => IdentityEncoding::Hex,
+                Encoding::Base32 => IdentityEncoding::Base32,
+            },
+            self_describing: self.self_describing,
+        }
+    }
+}
+
+impl InputArgs {
+    /// Build the include graph of the root file, reporting unresolved includes.
+    fn build(&self) -> Result<(IncludeNodeGraph, NodeIndex), Box<dyn Error>> {
+        let file = self
+            .file
+            .canonicalize()
+            .map_err(|error| format!("{}: {}", self.file.display(), error))?;
+        let working_dir = match self.working_dir {
+            Some(ref working_dir) => working_dir.clone(),
+            None => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        let defines = self
+            .defines
+            .iter()
+            .map(|define| match define.find('=') {
+                Some(index) => (define[..index].to_string(), define[index + 1..].to_string()),
+                None => (define.clone(), "1".to_string()),
+            })
+            .collect::<BTreeMap<String, String>>();
+        let options = BuildOptions {
+            normalize_endings: self.normalize_endings,
+            include_dirs: self.include_dirs.clone(),
+            strict: self.strict,
+            defines: if defines.is_empty() {
+                None
+            } else {
+                Some(defines)
+            },
+        };
+
+        let mut graph = IncludeNodeGraph::new();
+        let root_node = traverse_build_with(
+            &mut graph,
+            &working_dir,
+            &file,
+            0,
+            &options,
+            &DiskSourceProvider,
+        )?;
+        for missing in graph_missing_includes(&graph) {
+            eprintln!(
+                "warning: {}:{}:{}: unresolved include {:?}",
+                missing.includer.display(),
+                missing.line,
+                missing.column,
+                missing.spelled_path
+            );
+        }
+        Ok((graph, root_node))
+    }
+
+    fn patch_options(&self, identity: &IdentityArgs) -> PatchOptions {
+        PatchOptions {
+            normalize_endings: self.normalize_endings,
+            identity: identity.format(),
+            ..Default::default()
+        }
+    }
+}
+
+fn main() {
+    if let Err(error) = run(Cli::parse()) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let mut stdout = io::stdout().lock();
+    match cli.command {
+        Command::Hash { input, identity } => {
+            let (mut graph, root_node) = input.build()?;
+            let options = input.patch_options(&identity);
+            traverse_patch_with(&mut graph, root_node, &options, &DiskSourceProvider);
+            writeln!(stdout, "{}", root_identity(&graph, root_node))?;
+        }
+        Command::Flatten {
+            input,
+            output,
+            include_once,
+            line_directives,
+        } => {
+            let (mut graph, root_node) = input.build()?;
+            let options = FlattenOptions {
+                normalize_endings: input.normalize_endings,
+                line_directives: match line_directives {
+                    LineDirectives::None => LineDirectiveStyle::None,
+                    LineDirectives::C => LineDirectiveStyle::C,
+                    LineDirectives::Glsl => LineDirectiveStyle::Glsl,
+                },
+                include_once,
+                ..Default::default()
+            };
+            traverse_flatten_with(&mut graph, root_node, &options, &DiskSourceProvider);
+            let flattened = &graph[root_node].node.flattened;
+            match output {
+                Some(output) => fs::write(output, flattened)?,
+                None => stdout.write_all(flattened.as_bytes())?,
+            }
+        }
+        Command::Patch {
+            input,
+            identity,
+            output,
+            sharded,
+            identity_prefix,
+            identity_suffix,
+        } => {
+            let (mut graph, root_node) = input.build()?;
+            let options = PatchOptions {
+                identity_prefix: identity_prefix.clone(),
+                identity_suffix: identity_suffix.clone(),
+                ..input.patch_options(&identity)
+            };
+            traverse_patch_with(&mut graph, root_node, &options, &DiskSourceProvider);
+            let store = ContentStore::new(
+                &output,
+                ContentStoreOptions {
+                    identity: options.identity,
+                    sharded,
+                    identity_prefix,
+                    identity_suffix,
+                },
+            );
+            store.publish_graph(&graph)?;
+            writeln!(stdout, "{}", root_identity(&graph, root_node))?;
+        }
+        Command::Graph {
+            input,
+            identity,
+            format,
+        } => {
+            let (mut graph, root_node) = input.build()?;
+            let options = input.patch_options(&identity);
+            traverse_patch_with(&mut graph, root_node, &options, &DiskSourceProvider);
+            match format {
+                GraphFormat::Tree => graph_to_stdout(&graph, root_node)?,
+                GraphFormat::Dot => writeln!(stdout, "{}", graph_to_dot(&graph))?,
+                GraphFormat::Json => {
+                    let json = graph_to_json(&graph, root_node);
+                    writeln!(stdout, "{}", serde_json::to_string_pretty(&json)?)?;
+                }
+            }
+        }
+        Command::Deps {
+            input,
+            make_target,
+            phony_targets,
+        } => {
+            let (graph, root_node) = input.build()?;
+            match make_target {
+                Some(target) => write!(
+                    stdout,
+                    "{}",
+                    graph_to_depfile(&graph, root_node, &target, phony_targets)
+                )?,
+                None => {
+                    for dependency in graph_dependencies(&graph, root_node) {
+                        writeln!(stdout, "{}", dependency.display())?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get the identity of the root node; the salted identity if set, otherwise the patched identity.
+fn root_identity(graph: &IncludeNodeGraph, root_node: NodeIndex) -> &str {
+    let root = &graph[root_node].node;
+    root.salted_identity
+        .as_ref()
+        .or(root.patched_identity.as_ref())
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+/// Get a JSON representation of the graph; nodes are referenced by their index.
+fn graph_to_json(graph: &IncludeNodeGraph, root_node: NodeIndex) -> serde_json::Value {
+    let nodes = graph
+        .node_indices()
+        .map(|node_index| {
+            let weight = &graph[node_index];
+            let includes = weight
+                .includes
+                .iter()
+                .map(|include| {
+                    serde_json::json!({
+                        "path": include.include_path,
+                        "line": include.line,
+                        "column": include.column,
+                    })
+                })
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "index": node_index.index(),
+                "file": weight.node.include_file,
+                "source_identity": weight.node.source_identity,
+                "patched_identity": weight.node.patched_identity,
+                "missing": weight.missing.is_some(),
+                "includes": includes,
+            })
+        })
+        .collect::<Vec<_>>();
+    let edges = graph
+        .raw_edges()
+        .iter()
+        .map(|edge| {
+            serde_json::json!({
+                "from": edge.source().index(),
+                "to": edge.target().index(),
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "root": root_node.index(),
+        "nodes": nodes,
+        "edges": edges,
+    })
+}
@@ -0,0 +1,347 @@
+//! Helpers for compiling and embedding shaders from a Cargo build script.
+//!
+//! `build_script` builds every entry point into a shared workspace, writes the patched files (and
+//! optionally the flattened entry points) named by identity, prints `cargo:rerun-if-changed` for
+//! every file in the graph (and every directory an unresolved include could be created in), and
+//! generates a Rust module of constants to `include!`:
+//!
+//! ```ignore
+//! // build.rs
+//! let entry_points = [PathBuf::from("shaders/Blit.hlsl")];
+//! include_merkle::build_script(Path::new("shaders"), &entry_points, &Default::default())?;
+//!
+//! // lib.rs
+//! include!(concat!(env!("OUT_DIR"), "/include_merkle.rs"));
+//! ```
+
+use crate::{
+    graph_dependencies, graph_missing_includes, traverse_flatten_with, BuildOptions,
+    DiskSourceProvider, FlattenOptions, IncludeWorkspace, PatchOptions, SourceProvider,
+};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Options controlling `build_script`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct BuildScriptOptions {
+    /// Options used to build the include graphs
+    pub build: BuildOptions,
+
+    /// Options used to patch the include graphs; patched files are named `identity_prefix`, the
+    /// identity, then `identity_suffix`, matching the patched include directives
+    pub patch: PatchOptions,
+
+    /// Also flatten every entry point (named by the identity of the flattened text, keeping the
+    /// extension of the entry point) when set
+    pub flatten: Option<FlattenOptions>,
+
+    /// Directory the outputs are written to; `OUT_DIR` when not set
+    pub output_dir: Option<PathBuf>,
+}
+
+/// The outputs of one entry point.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct BuildScriptEntryPoint {
+    /// Canonical path of the entry point
+    pub entry_point: PathBuf,
+
+    /// Name of the constant holding the identity in the generated module (e.g. `BLIT_CS` for
+    /// `BlitCS.hlsl`); the other constants add a suffix to it
+    pub const_name: String,
+
+    /// Patched (or salted, when `PatchOptions::context` is set) identity of the entry point
+    pub identity: String,
+
+    /// Path of the patched entry point
+    pub patched_file: PathBuf,
+
+    /// Identity and path of the flattened entry point, when flattening
+    pub flattened: Option<(String, PathBuf)>,
+}
+
+/// The outputs of `build_script`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct BuildScriptOutput {
+    /// Path of the generated Rust module (`include_merkle.rs` in the output directory)
+    pub module_file: PathBuf,
+
+    /// Outputs of every entry point, in the given order
+    pub entry_points: Vec<BuildScriptEntryPoint>,
+
+    /// Files and directories printed as `cargo:rerun-if-changed`
+    pub rerun_if_changed: Vec<PathBuf>,
+}
+
+/// Build, patch (and optionally flatten) shader entry points from a Cargo build script.
+///
+/// The generated module has, for each entry point, a `<NAME>` constant with its identity and a
+/// `<NAME>_PATCHED` constant embedding its patched text; when flattening, `<NAME>_FLATTENED`
+/// embeds the flattened text and `<NAME>_FLATTENED_IDENTITY` holds its identity.
+pub fn build_script(
+    working_dir: &Path,
+    entry_points: &[PathBuf],
+    options: &BuildScriptOptions,
+) -> io::Result<BuildScriptOutput> {
+    let output_dir = match options.output_dir {
+        Some(ref output_dir) => output_dir.clone(),
+        None => std::env::var_os("OUT_DIR")
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "OUT_DIR is not set; run from a build script or set an output directory",
+                )
+            })?,
+    };
+    fs::create_dir_all(&output_dir)?;
+
+    let provider = DiskSourceProvider;
+    let mut workspace =
+        IncludeWorkspace::new(working_dir, options.build.clone(), options.patch.clone());
+    let mut root_nodes = Vec::with_capacity(entry_points.len());
+    for entry_point in entry_points {
+        if provider.canonicalize(entry_point).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("entry point {} not found", entry_point.display()),
+            ));
+        }
+        let root_node = workspace
+            .add_root(entry_point, &provider)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        root_nodes.push(root_node);
+    }
+    workspace.patch(&provider);
+    let graph = workspace.graph();
+
+    // Rebuild when any file in the graph changes.
+    let mut dependencies = HashSet::new();
+    let mut rerun_if_changed = Vec::new();
+    for root_node in &root_nodes {
+        for dependency in graph_dependencies(graph, *root_node) {
+            if dependency.exists() && dependencies.insert(dependency.clone()) {
+                println!("cargo:rerun-if-changed={}", dependency.display());
+                rerun_if_changed.push(dependency);
+            }
+        }
+    }
+    for missing in graph_missing_includes(graph) {
+        println!(
+            "cargo:warning={}:{}: unresolved include {:?}",
+            missing.includer.display(),
+            missing.line,
+            missing.spelled_path
+        );
+
+        // Also rebuild when the missing file is created in a directory it would be found in.
+        let includer_dir = missing.includer.parent().filter(|_| missing.relative_path);
+        let search_dirs = std::iter::once(working_dir)
+            .chain(options.build.include_dirs.iter().map(PathBuf::as_path));
+        for dir in includer_dir.into_iter().chain(search_dirs) {
+            if dir.is_dir() && dependencies.insert(dir.to_path_buf()) {
+                println!("cargo:rerun-if-changed={}", dir.display());
+                rerun_if_changed.push(dir.to_path_buf());
+            }
+        }
+    }
+
+    // The patched files are content-addressed, so existing files are already up to date.
+    let patched_file = |identity: &str| {
+        output_dir.join(format!(
+            "{}{}{}",
+            options.patch.identity_prefix, identity, options.patch.identity_suffix
+        ))
+    };
+    for node in graph.raw_nodes() {
+        if let Some(ref patched_identity) = node.weight.node.patched_identity {
+            write_output(&patched_file(patched_identity), &node.weight.node.flattened)?;
+        }
+    }
+
+    let mut flattened_graph = options.flatten.as_ref().map(|_| graph.clone());
+    let mut outputs = Vec::with_capacity(entry_points.len());
+    let mut module = String::from("// Generated by include-merkle; do not edit.\n");
+    for root_node in &root_nodes {
+        let root = &graph[*root_node].node;
+        let patched_identity = root.patched_identity.clone().unwrap_or_default();
+        let identity = root
+            .salted_identity
+            .clone()
+            .unwrap_or_else(|| patched_identity.clone());
+        let const_name = const_name(&root.include_file).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} has no ASCII letters or digits to name a constant",
+                    root.include_file.display()
+                ),
+            )
+        })?;
+        if outputs
+            .iter()
+            .any(|output: &BuildScriptEntryPoint| output.const_name == const_name)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} and another entry point both map to the constant {}",
+                    root.include_file.display(),
+                    const_name
+                ),
+            ));
+        }
+
+        let mut output = BuildScriptEntryPoint {
+            entry_point: root.include_file.clone(),
+            const_name,
+            identity,
+            patched_file: patched_file(&patched_identity),
+            flattened: None,
+        };
+        writeln!(
+            module,
+            "\n/// Identity of `{}`\npub const {}: &str = {:?};",
+            output.entry_point.display(),
+            output.const_name,
+            output.identity
+        )
+        .unwrap();
+        writeln!(
+            module,
+            "/// Patched text of `{}`\npub const {}_PATCHED: &str = include_str!({:?});",
+            output.entry_point.display(),
+            output.const_name,
+            output.patched_file
+        )
+        .unwrap();
+
+        if let (Some(flatten), Some(flattened_graph)) = (&options.flatten, &mut flattened_graph) {
+            traverse_flatten_with(flattened_graph, *root_node, flatten, &provider);
+            let flattened = &flattened_graph[*root_node].node.flattened;
+            let flattened_identity = flatten.identity.compute(flattened.as_bytes());
+            let flattened_file = match output.entry_point.extension() {
+                Some(extension) => output_dir.join(format!(
+                    "{}.{}",
+                    flattened_identity,
+                    extension.to_string_lossy()
+                )),
+                None => output_dir.join(&flattened_identity),
+            };
+            write_output(&flattened_file, flattened)?;
+            writeln!(
+                module,
+                "/// Flattened text of `{}`\npub const {}_FLATTENED: &str = include_str!({:?});",
+                output.entry_point.display(),
+                output.const_name,
+                flattened_file
+            )
+            .unwrap();
+            writeln!(
+                module,
+                "/// Identity of the flattened text of `{}`\npub const {}_FLATTENED_IDENTITY: &str = {:?};",
+                output.entry_point.display(),
+                output.const_name,
+                flattened_identity
+            )
+            .unwrap();
+            output.flattened = Some((flattened_identity, flattened_file));
+        }
+        outputs.push(output);
+    }
+
+    let module_file = output_dir.join("include_merkle.rs");
+    fs::write(&module_file, module)?;
+    Ok(BuildScriptOutput {
+        module_file,
+        entry_points: outputs,
+        rerun_if_changed,
+    })
+}
+
+/// Write a content-addressed output file, unless it already exists.
+fn write_output(path: &Path, text: &str) -> io::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, text)
+}
+
+/// Get the constant name of an entry point: its file stem in upper snake case, or `None` if the
+/// stem has no ASCII letters or digits.
+fn const_name(entry_point: &Path) -> Option<String> {
+    let stem = entry_point
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .chars()
+        .collect::<Vec<char>>();
+    let mut name = String::with_capacity(stem.len() + 4);
+    for (index, c) in stem.iter().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            if !name.is_empty() && !name.ends_with('_') {
+                name.push('_');
+            }
+            continue;
+        }
+        // Start a new word at `aB`, `1B` and the `B` of `ABc` (so `HdrCS` becomes `HDR_CS`).
+        if c.is_ascii_uppercase() && index > 0 && !name.ends_with('_') {
+            let previous = stem[index - 1];
+            let next = stem.get(index + 1);
+            if previous.is_ascii_lowercase()
+                || previous.is_ascii_digit()
+                || (previous.is_ascii_uppercase() && next.is_some_and(char::is_ascii_lowercase))
+            {
+                name.push('_');
+            }
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    let name = name.trim_end_matches('_');
+    if name.is_empty() {
+        None
+    } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+        Some(format!("_{}", name))
+    } else {
+        Some(name.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn const_names() {
+        let cases = [
+            ("Blit.hlsl", "BLIT"),
+            ("HdrCS.hlsl", "HDR_CS"),
+            ("blitCS.hlsl", "BLIT_CS"),
+            // A leading digit isn't a valid identifier.
+            ("2DBlit.hlsl", "_2_D_BLIT"),
+            ("3d_blit.hlsl", "_3D_BLIT"),
+            // Punctuation and non-ASCII characters separate words.
+            ("my-shader (copy).v2.hlsl", "MY_SHADER_COPY_V2"),
+            ("__blit__.hlsl", "BLIT"),
+            ("Blür.hlsl", "BL_R"),
+            ("Ωmega.hlsl", "MEGA"),
+        ];
+        for (file, name) in cases.iter() {
+            assert_eq!(
+                const_name(Path::new(file)).as_deref(),
+                Some(*name),
+                "{}",
+                file
+            );
+        }
+
+        // Nothing is left to name a constant with.
+        assert_eq!(const_name(Path::new("シェーダー.hlsl")), None);
+        assert_eq!(const_name(Path::new("-.hlsl")), None);
+    }
+}
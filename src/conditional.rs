@@ -0,0 +1,663 @@
+//! Evaluation of conditional compilation directives.
+//!
+//! Tracks `#define` and `#undef` directives and evaluates `#if`, `#ifdef`, `#ifndef`, `#elif`,
+//! `#else` and `#endif` so that only directives on active branches are followed. Expressions
+//! support `defined`, integer literals, object-like macro expansion and the C integer operators;
+//! identifiers that aren't macros (and function-like macro invocations) evaluate to zero.
+
+use crate::scan::Directive;
+use log::trace;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Macros defined at a point in a translation unit.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MacroTable {
+    /// Object-like macros and their replacement text.
+    objects: HashMap<String, String>,
+
+    /// Names of function-like macros.
+    functions: HashSet<String>,
+}
+
+impl MacroTable {
+    /// Create a table of predefined object-like macros.
+    pub fn new(defines: &BTreeMap<String, String>) -> Self {
+        MacroTable {
+            objects: defines
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            functions: HashSet::new(),
+        }
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.objects.contains_key(name) || self.functions.contains(name)
+    }
+
+    /// Apply the body of a `#define` directive.
+    fn define(&mut self, body: &str) {
+        let body = body.trim_start();
+        let name_end = body
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(body.len());
+        let name = &body[..name_end];
+        if name.is_empty() {
+            return;
+        }
+
+        self.undefine(name);
+        if body[name_end..].starts_with('(') {
+            self.functions.insert(name.to_string());
+        } else {
+            self.objects
+                .insert(name.to_string(), body[name_end..].trim().to_string());
+        }
+    }
+
+    /// Apply the body of an `#undef` directive.
+    fn undefine(&mut self, body: &str) {
+        if let Some(name) = body.split_whitespace().next() {
+            self.objects.remove(name);
+            self.functions.remove(name);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Branch {
+    /// Lines of the current branch are active.
+    active: bool,
+
+    /// A branch of the conditional has already been taken.
+    taken: bool,
+
+    /// Lines enclosing the conditional are active.
+    enclosing: bool,
+}
+
+/// The stack of open conditionals while walking the directives of a file.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Conditions {
+    stack: Vec<Branch>,
+}
+
+impl Conditions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Determine whether lines at the current position are active.
+    pub fn is_active(&self) -> bool {
+        self.stack.last().is_none_or(|branch| branch.active)
+    }
+
+    /// Apply a conditional, `#define` or `#undef` directive; other directives are ignored.
+    pub fn apply(&mut self, directive: &Directive, macros: &mut MacroTable) {
+        let body = directive.body.as_str();
+        match directive.name.as_str() {
+            "if" | "ifdef" | "ifndef" => {
+                let enclosing = self.is_active();
+                let active = enclosing && condition(&directive.name, body, macros);
+                self.stack.push(Branch {
+                    active,
+                    taken: active,
+                    enclosing,
+                });
+            }
+            "elif" | "elifdef" | "elifndef" => {
+                if let Some(branch) = self.stack.last_mut() {
+                    branch.active = branch.enclosing
+                        && !branch.taken
+                        && condition(&directive.name, body, macros);
+                    branch.taken |= branch.active;
+                }
+            }
+            "else" => {
+                if let Some(branch) = self.stack.last_mut() {
+                    branch.active = branch.enclosing && !branch.taken;
+                    branch.taken = true;
+                }
+            }
+            "endif" => {
+                self.stack.pop();
+            }
+            "define" if self.is_active() => macros.define(body),
+            "undef" if self.is_active() => macros.undefine(body),
+            _ => {}
+        }
+    }
+}
+
+/// Evaluate the condition of an `#if`, `#ifdef` or `#ifndef` (or `#elif...`) directive.
+fn condition(name: &str, body: &str, macros: &MacroTable) -> bool {
+    let operand = || body.split_whitespace().next().unwrap_or_default();
+    match name {
+        "ifdef" | "elifdef" => macros.is_defined(operand()),
+        "ifndef" | "elifndef" => !macros.is_defined(operand()),
+        _ => match evaluate(body, macros) {
+            Some(value) => value != 0,
+            None => {
+                trace!(
+                    "Failed to evaluate conditional expression {:?}",
+                    body.trim()
+                );
+                false
+            }
+        },
+    }
+}
+
+/// Evaluate a preprocessor integer expression, returning `None` if it is malformed.
+pub(crate) fn evaluate(expression: &str, macros: &MacroTable) -> Option<i64> {
+    let tokens = expand(tokenize(expression)?, macros, &mut Vec::new())?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+    };
+    let value = parser.ternary()?;
+    if parser.position == tokens.len() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Identifier(String),
+    Punct(&'static str),
+}
+
+const PUNCTUATORS: [&str; 26] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "!", "~", "-", "+", "*", "/", "%", "<", ">",
+    "&", "^", "|", "?", ":", "(", ")", ",", "#",
+];
+
+fn tokenize(expression: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_number(&rest[..end])?));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Identifier(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            let punct = PUNCTUATORS.iter().find(|punct| rest.starts_with(**punct))?;
+            tokens.push(Token::Punct(punct));
+            rest = &rest[punct.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Some(tokens)
+}
+
+/// Parse a decimal, hexadecimal, binary or octal integer literal with optional suffixes.
+fn parse_number(literal: &str) -> Option<i64> {
+    let digits = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+    let (digits, radix) = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        (hex, 16)
+    } else if let Some(binary) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        (binary, 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (&digits[1..], 8)
+    } else {
+        (digits, 10)
+    };
+    u64::from_str_radix(digits, radix)
+        .ok()
+        .map(|value| value as i64)
+}
+
+/// Replace `defined` operators and macros by their values; remaining identifiers become zero.
+fn expand(
+    tokens: Vec<Token>,
+    macros: &MacroTable,
+    expanding: &mut Vec<String>,
+) -> Option<Vec<Token>> {
+    let mut expanded = Vec::new();
+    let mut iter = tokens.into_iter().peekable();
+    while let Some(token) = iter.next() {
+        let name = match token {
+            Token::Identifier(name) => name,
+            token => {
+                expanded.push(token);
+                continue;
+            }
+        };
+
+        if name == "defined" {
+            let parenthesized = iter.peek() == Some(&Token::Punct("("));
+            if parenthesized {
+                iter.next();
+            }
+            let operand = match iter.next() {
+                Some(Token::Identifier(operand)) => operand,
+                _ => return None,
+            };
+            if parenthesized && iter.next() != Some(Token::Punct(")")) {
+                return None;
+            }
+            expanded.push(Token::Number(macros.is_defined(&operand) as i64));
+        } else if let Some(value) = macros
+            .objects
+            .get(&name)
+            .filter(|_| !expanding.contains(&name))
+        {
+            expanding.push(name);
+            expanded.extend(expand(tokenize(value)?, macros, expanding)?);
+            expanding.pop();
+        } else {
+            if macros.functions.contains(&name) && iter.peek() == Some(&Token::Punct("(")) {
+                // Skip the argument list of a function-like macro invocation.
+                let mut depth = 0;
+                for token in iter.by_ref() {
+                    match token {
+                        Token::Punct("(") => depth += 1,
+                        Token::Punct(")") => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            expanded.push(Token::Number(0));
+        }
+    }
+    Some(expanded)
+}
+
+/// Operator precedence parser over expanded tokens.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek_punct(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Punct(punct)) => Some(punct),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Option<()> {
+        if self.peek_punct() == Some(punct) {
+            self.position += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn ternary(&mut self) -> Option<i64> {
+        let condition = self.binary(0)?;
+        if self.expect("?").is_none() {
+            return Some(condition);
+        }
+        let if_true = self.ternary()?;
+        self.expect(":")?;
+        let if_false = self.ternary()?;
+        Some(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn binary(&mut self, min_precedence: u32) -> Option<i64> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek_punct() {
+            let precedence = match precedence(op) {
+                Some(precedence) if precedence >= min_precedence => precedence,
+                _ => break,
+            };
+            self.position += 1;
+            let rhs = self.binary(precedence + 1)?;
+            lhs = apply_binary(op, lhs, rhs);
+        }
+        Some(lhs)
+    }
+
+    fn unary(&mut self) -> Option<i64> {
+        match self.tokens.get(self.position)? {
+            Token::Number(value) => {
+                self.position += 1;
+                Some(*value)
+            }
+            Token::Punct("(") => {
+                self.position += 1;
+                let value = self.ternary()?;
+                self.expect(")")?;
+                Some(value)
+            }
+            Token::Punct(op @ ("!" | "~" | "-" | "+")) => {
+                self.position += 1;
+                let value = self.unary()?;
+                Some(match *op {
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    "-" => value.wrapping_neg(),
+                    _ => value,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn precedence(op: &str) -> Option<u32> {
+    Some(match op {
+        "||" => 0,
+        "&&" => 1,
+        "|" => 2,
+        "^" => 3,
+        "&" => 4,
+        "==" | "!=" => 5,
+        "<" | ">" | "<=" | ">=" => 6,
+        "<<" | ">>" => 7,
+        "+" | "-" => 8,
+        "*" | "/" | "%" => 9,
+        _ => return None,
+    })
+}
+
+fn apply_binary(op: &str, lhs: i64, rhs: i64) -> i64 {
+    match op {
+        "||" => (lhs != 0 || rhs != 0) as i64,
+        "&&" => (lhs != 0 && rhs != 0) as i64,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "<" => (lhs < rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        // Division by zero evaluates to zero rather than failing the whole expression.
+        "/" => lhs.checked_div(rhs).unwrap_or(0),
+        "%" => lhs.checked_rem(rhs).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::scan_directives;
+    use crate::{
+        traverse_build_with, BuildOptions, IncludeError, IncludeNodeGraph, MemorySourceProvider,
+    };
+    use std::path::{Path, PathBuf};
+
+    fn macros(defines: &[(&str, &str)]) -> MacroTable {
+        MacroTable::new(
+            &defines
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    /// Walk the directives of a text, returning the body of every `#include` on an active branch.
+    fn active_includes(text: &str, macros: &mut MacroTable) -> Vec<String> {
+        let mut conditions = Conditions::new();
+        let mut active = Vec::new();
+        for directive in scan_directives(text) {
+            if directive.name == "include" {
+                if conditions.is_active() {
+                    active.push(directive.body.trim().to_string());
+                }
+            } else {
+                conditions.apply(&directive, macros);
+            }
+        }
+        active
+    }
+
+    #[test]
+    fn evaluate_defined() {
+        let macros = macros(&[("A", "")]);
+        assert_eq!(evaluate("defined A", &macros), Some(1));
+        assert_eq!(evaluate("defined(A) && !defined ( B )", &macros), Some(1));
+        assert_eq!(evaluate("defined(B) || defined B", &macros), Some(0));
+        assert_eq!(evaluate("defined", &macros), None);
+        assert_eq!(evaluate("defined(A", &macros), None);
+    }
+
+    #[test]
+    fn evaluate_operators() {
+        let macros = MacroTable::default();
+        assert_eq!(evaluate("1 + 2 * 3", &macros), Some(7));
+        assert_eq!(evaluate("(1 + 2) * 3", &macros), Some(9));
+        assert_eq!(evaluate("0x10 | 0b1 | 010", &macros), Some(25));
+        assert_eq!(evaluate("1 << 4 >= 16 && 3 % 2", &macros), Some(1));
+        assert_eq!(evaluate("-1 < 0 ? 10 : 20", &macros), Some(10));
+        assert_eq!(evaluate("4 / 0", &macros), Some(0));
+        assert_eq!(evaluate("100UL == 100", &macros), Some(1));
+        assert_eq!(evaluate("1 +", &macros), None);
+        assert_eq!(evaluate("(1", &macros), None);
+        assert_eq!(evaluate("1 2", &macros), None);
+    }
+
+    #[test]
+    fn evaluate_macro_expansion() {
+        let mut macros = macros(&[("VERSION", "MAJOR * 100 + MINOR"), ("MAJOR", "6")]);
+        macros.define("MINOR 6");
+        macros.define("SELF SELF + 1");
+        macros.define("FUNC(x) (x)");
+        assert_eq!(evaluate("VERSION", &macros), Some(606));
+        assert_eq!(evaluate("VERSION >= 605", &macros), Some(1));
+        assert_eq!(evaluate("UNDEFINED", &macros), Some(0));
+        assert_eq!(evaluate("SELF", &macros), Some(1));
+        assert_eq!(evaluate("FUNC(1 + (2)) + 3", &macros), Some(3));
+        assert!(macros.is_defined("FUNC"));
+    }
+
+    #[test]
+    fn elif_and_else_after_taken_branch() {
+        let text = "\
+#if 1
+#include \"a\"
+#elif 1
+#include \"b\"
+#else
+#include \"c\"
+#endif
+#if 0
+#include \"d\"
+#elif 2 > 1
+#include \"e\"
+#elif 1
+#include \"f\"
+#else
+#include \"g\"
+#endif
+#ifdef MISSING
+#include \"h\"
+#elifndef MISSING
+#include \"i\"
+#endif
+";
+        let active = active_includes(text, &mut MacroTable::default());
+        assert_eq!(active, ["\"a\"", "\"e\"", "\"i\""]);
+    }
+
+    #[test]
+    fn nested_inactive_blocks() {
+        let text = "\
+#if 0
+#if 1
+#include \"a\"
+#else
+#include \"b\"
+#endif
+#define INSIDE
+#elif 1
+#include \"c\"
+#endif
+#ifdef INSIDE
+#include \"d\"
+#endif
+";
+        let mut macros = MacroTable::default();
+        assert_eq!(active_includes(text, &mut macros), ["\"c\""]);
+        assert!(!macros.is_defined("INSIDE"));
+    }
+
+    #[test]
+    fn define_and_undef() {
+        let text = "\
+#define A 2
+#if A == 2
+#include \"a\"
+#endif
+#undef A
+#ifdef A
+#include \"b\"
+#endif
+#if A == 0
+#include \"c\"
+#endif
+#undef P
+#ifndef P
+#include \"d\"
+#endif
+";
+        let mut macros = macros(&[("P", "1")]);
+        assert_eq!(
+            active_includes(text, &mut macros),
+            ["\"a\"", "\"c\"", "\"d\""]
+        );
+        assert!(!macros.is_defined("A"));
+    }
+
+    fn active_include_files(common: &str) -> Vec<PathBuf> {
+        let mut provider = MemorySourceProvider::new();
+        provider.insert(
+            "/shaders/Root.hlsl",
+            "#define USE_A\n#include \"Common.hlsli\"\n#undef USE_A\n#include \"Common.hlsli\"\n",
+        );
+        provider.insert("/shaders/Common.hlsli", common);
+        provider.insert("/shaders/A.hlsli", "");
+        provider.insert("/shaders/B.hlsli", "");
+
+        let mut graph = IncludeNodeGraph::new();
+        let options = BuildOptions {
+            defines: Some(BTreeMap::new()),
+            ..Default::default()
+        };
+        traverse_build_with(
+            &mut graph,
+            Path::new("/shaders"),
+            Path::new("/shaders/Root.hlsl"),
+            0,
+            &options,
+            &provider,
+        )
+        .expect("failed to build include graph");
+        let common = graph
+            .node_indices()
+            .find(|node| graph[*node].node.include_file == Path::new("/shaders/Common.hlsli"))
+            .unwrap();
+        graph[common]
+            .includes
+            .iter()
+            .map(|include| include.include_path.clone())
+            .collect()
+    }
+
+    #[test]
+    fn active_includes_of_repeated_header() {
+        let common = "#ifdef USE_A\n#include \"A.hlsli\"\n#else\n#include \"B.hlsli\"\n#endif\n";
+
+        // Every inclusion is evaluated, and the active includes are the union of them.
+        assert_eq!(
+            active_include_files(common),
+            [
+                PathBuf::from("/shaders/A.hlsli"),
+                PathBuf::from("/shaders/B.hlsli")
+            ]
+        );
+
+        // The second inclusion of a guarded header is entirely inactive.
+        let guarded = format!("#ifndef COMMON\n#define COMMON\n{}#endif\n", common);
+        assert_eq!(
+            active_include_files(&guarded),
+            [PathBuf::from("/shaders/A.hlsli")]
+        );
+    }
+
+    fn build_mutual_includes(a: &str, b: &str) -> Result<IncludeNodeGraph, IncludeError> {
+        let mut provider = MemorySourceProvider::new();
+        provider.insert("/shaders/Root.hlsl", "#include \"A.hlsli\"\n");
+        provider.insert("/shaders/A.hlsli", a);
+        provider.insert("/shaders/B.hlsli", b);
+        let mut graph = IncludeNodeGraph::new();
+        let options = BuildOptions {
+            defines: Some(BTreeMap::new()),
+            ..Default::default()
+        };
+        traverse_build_with(
+            &mut graph,
+            Path::new("/shaders"),
+            Path::new("/shaders/Root.hlsl"),
+            0,
+            &options,
+            &provider,
+        )?;
+        Ok(graph)
+    }
+
+    #[test]
+    fn guarded_mutual_includes() {
+        let a = "#ifndef A\n#define A\n#include \"B.hlsli\"\n#endif\n";
+        let b = "#ifndef B\n#define B\n#include \"A.hlsli\"\n#endif\n";
+        let graph = build_mutual_includes(a, b).expect("failed to build include graph");
+
+        // The include of `A.hlsli` from `B.hlsli` stops at the guard of `A.hlsli`.
+        let b = graph
+            .node_indices()
+            .find(|node| graph[*node].node.include_file == Path::new("/shaders/B.hlsli"))
+            .unwrap();
+        assert!(graph[b].includes.is_empty());
+        assert_eq!(graph[b].inactive_includes.len(), 1);
+        assert_eq!(graph.edge_count(), 2);
+
+        // A guarded file including itself is fine too.
+        let a = "#ifndef A\n#define A\n#include \"A.hlsli\"\n#endif\n";
+        assert!(build_mutual_includes(a, "").is_ok());
+    }
+
+    #[test]
+    fn unguarded_mutual_includes() {
+        let a = "#include \"B.hlsli\"\n";
+        let b = "#ifndef B\n#define B\n#include \"A.hlsli\"\n#endif\n";
+        assert!(matches!(
+            build_mutual_includes(a, b),
+            Err(IncludeError::Cycle { .. })
+        ));
+        assert!(matches!(
+            build_mutual_includes(a, a),
+            Err(IncludeError::Cycle { .. })
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+//! Dependency lists for build systems.
+//!
+//! Depfiles follow the GNU Make syntax written by `gcc -MD -MP`, which Ninja and CMake also read.
+
+use crate::IncludeNodeGraph;
+use petgraph::prelude::*;
+use petgraph::visit::Walker;
+use std::path::{Path, PathBuf};
+
+/// Get every file a root depends on (the root itself, then the files it includes in depth-first
+/// order); unresolved includes are skipped.
+pub fn graph_dependencies(graph: &IncludeNodeGraph, root_node: NodeIndex) -> Vec<PathBuf> {
+    Dfs::new(graph, root_node)
+        .iter(graph)
+        .filter(|node_index| graph[*node_index].missing.is_none())
+        .map(|node_index| graph[node_index].node.include_file.clone())
+        .collect()
+}
+
+/// Get a GNU Make-style depfile declaring that `target` depends on every file the root depends
+/// on.
+///
+/// With `phony_targets`, an empty rule is added for every included file (like `gcc -MP`), so
+/// deleting a header doesn't break the build before the depfile is regenerated.
+pub fn graph_to_depfile(
+    graph: &IncludeNodeGraph,
+    root_node: NodeIndex,
+    target: &Path,
+    phony_targets: bool,
+) -> String {
+    let dependencies = graph_dependencies(graph, root_node);
+    let mut depfile = format!("{}:", escape_make_path(target));
+    for dependency in &dependencies {
+        depfile.push_str(" \\\n  ");
+        depfile.push_str(&escape_make_path(dependency));
+    }
+    depfile.push('\n');
+    if phony_targets {
+        for dependency in dependencies.iter().skip(1) {
+            depfile.push('\n');
+            depfile.push_str(&escape_make_path(dependency));
+            depfile.push_str(":\n");
+        }
+    }
+    depfile
+}
+
+/// Get the `cargo:rerun-if-changed` lines for every file the root depends on, for a build script
+/// to print.
+pub fn graph_to_rerun_if_changed(graph: &IncludeNodeGraph, root_node: NodeIndex) -> String {
+    graph_dependencies(graph, root_node)
+        .iter()
+        .map(|dependency| format!("cargo:rerun-if-changed={}\n", dependency.display()))
+        .collect()
+}
+
+/// Escape a path for a Make rule the way `gcc` does: spaces (and any backslashes before them)
+/// and `#` are escaped with a backslash, and `$` is doubled.
+fn escape_make_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let mut escaped = String::with_capacity(path.len());
+    let mut backslashes = 0;
+    for c in path.chars() {
+        match c {
+            ' ' | '\t' => {
+                escaped.extend(std::iter::repeat_n('\\', backslashes + 1));
+                escaped.push(c);
+            }
+            '#' => escaped.push_str("\\#"),
+            '$' => escaped.push_str("$$"),
+            _ => escaped.push(c),
+        }
+        backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{traverse_build_with, BuildOptions, MemorySourceProvider};
+
+    #[test]
+    fn escape_make_paths() {
+        let escape = |path: &str| escape_make_path(Path::new(path));
+        assert_eq!(escape("/shaders/Blit.hlsl"), "/shaders/Blit.hlsl");
+        assert_eq!(escape("/my shaders/a\tb.hlsl"), "/my\\ shaders/a\\\tb.hlsl");
+        assert_eq!(escape("/a\\ b"), "/a\\\\\\ b");
+        assert_eq!(escape("/a\\\\ b"), "/a\\\\\\\\\\ b");
+        assert_eq!(escape("C:\\shaders\\a.hlsl"), "C:\\shaders\\a.hlsl");
+        assert_eq!(escape("/#1/$(x).hlsl"), "/\\#1/$$(x).hlsl");
+    }
+
+    #[test]
+    fn depfile_with_phony_targets() {
+        let mut provider = MemorySourceProvider::new();
+        provider.insert("/shaders/Blit.hlsl", "#include \"Common Lib.hlsli\"\n");
+        provider.insert("/shaders/Common Lib.hlsli", "#include \"$Defs.hlsli\"\n");
+        provider.insert("/shaders/$Defs.hlsli", "");
+        let mut graph = IncludeNodeGraph::new();
+        let root_node = traverse_build_with(
+            &mut graph,
+            Path::new("/shaders"),
+            Path::new("/shaders/Blit.hlsl"),
+            0,
+            &BuildOptions::default(),
+            &provider,
+        )
+        .unwrap();
+
+        let target = Path::new("/out/Blit #1.dxil");
+        assert_eq!(
+            graph_to_depfile(&graph, root_node, target, false),
+            "/out/Blit\\ \\#1.dxil: \\\n  /shaders/Blit.hlsl \\\n  /shaders/Common\\ Lib.hlsli \\\n  /shaders/$$Defs.hlsli\n"
+        );
+        assert_eq!(
+            graph_to_depfile(&graph, root_node, target, true),
+            "/out/Blit\\ \\#1.dxil: \\\n  /shaders/Blit.hlsl \\\n  /shaders/Common\\ Lib.hlsli \\\n  /shaders/$$Defs.hlsli\n\n/shaders/Common\\ Lib.hlsli:\n\n/shaders/$$Defs.hlsli:\n"
+        );
+        assert_eq!(
+            graph_to_rerun_if_changed(&graph, root_node),
+            "cargo:rerun-if-changed=/shaders/Blit.hlsl\ncargo:rerun-if-changed=/shaders/Common Lib.hlsli\ncargo:rerun-if-changed=/shaders/$Defs.hlsli\n"
+        );
+    }
+}
@@ -0,0 +1,432 @@
+//! Hash algorithms and encodings used to compute identities.
+//!
+//! Self-describing identities follow the multiformats conventions: a multibase prefix character
+//! followed by the encoded multihash (varint algorithm code, varint digest length, digest).
+
+use base58::{FromBase58, ToBase58};
+use sha2::{Digest, Sha256};
+
+/// Hash algorithm used to compute identities.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    /// SHA-256
+    #[default]
+    Sha256,
+
+    /// BLAKE3 (256-bit), enabled with the `blake3` feature
+    #[cfg(feature = "blake3")]
+    Blake3,
+
+    /// XXH3 (64-bit), enabled with the `xxh3` feature; fast but not cryptographic, so only
+    /// suitable for local caches
+    #[cfg(feature = "xxh3")]
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    const ALL: &[HashAlgorithm] = &[
+        HashAlgorithm::Sha256,
+        #[cfg(feature = "blake3")]
+        HashAlgorithm::Blake3,
+        #[cfg(feature = "xxh3")]
+        HashAlgorithm::Xxh3,
+    ];
+
+    /// Compute the digest of a data slice.
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::default();
+                hasher.input(data);
+                hasher.result().to_vec()
+            }
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+            #[cfg(feature = "xxh3")]
+            HashAlgorithm::Xxh3 => xxhash_rust::xxh3::xxh3_64(data).to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Get the length in bytes of the digest.
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => 32,
+            #[cfg(feature = "xxh3")]
+            HashAlgorithm::Xxh3 => 8,
+        }
+    }
+
+    /// Get the multihash code of the algorithm.
+    pub fn multihash_code(self) -> u64 {
+        match self {
+            HashAlgorithm::Sha256 => 0x12,
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => 0x1e,
+            #[cfg(feature = "xxh3")]
+            HashAlgorithm::Xxh3 => 0xb3e3,
+        }
+    }
+}
+
+/// Text encoding of identities.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IdentityEncoding {
+    /// Base58 (Bitcoin alphabet)
+    #[default]
+    Base58,
+
+    /// Lowercase hexadecimal
+    Hex,
+
+    /// Lowercase RFC 4648 Base32 without padding; safe on case-insensitive file systems
+    Base32,
+}
+
+impl IdentityEncoding {
+    const ALL: [IdentityEncoding; 3] = [
+        IdentityEncoding::Base58,
+        IdentityEncoding::Hex,
+        IdentityEncoding::Base32,
+    ];
+
+    /// Encode bytes as text.
+    pub fn encode(self, data: &[u8]) -> String {
+        match self {
+            IdentityEncoding::Base58 => data.to_base58(),
+            IdentityEncoding::Hex => data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            IdentityEncoding::Base32 => {
+                let mut text = String::with_capacity((data.len() * 8).div_ceil(5));
+                let (mut buffer, mut bits) = (0u32, 0);
+                for byte in data {
+                    buffer = (buffer << 8) | u32::from(*byte);
+                    bits += 8;
+                    while bits >= 5 {
+                        bits -= 5;
+                        text.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+                    }
+                }
+                if bits > 0 {
+                    text.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+                }
+                text
+            }
+        }
+    }
+
+    /// Decode text produced by `encode`, returning `None` if it is malformed.
+    pub fn decode(self, text: &str) -> Option<Vec<u8>> {
+        match self {
+            IdentityEncoding::Base58 => text.from_base58().ok(),
+            IdentityEncoding::Hex => {
+                if !text.len().is_multiple_of(2) || !text.is_ascii() {
+                    return None;
+                }
+                (0..text.len())
+                    .step_by(2)
+                    .map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok())
+                    .collect()
+            }
+            IdentityEncoding::Base32 => {
+                let mut data = Vec::with_capacity(text.len() * 5 / 8);
+                let (mut buffer, mut bits) = (0u32, 0);
+                for c in text.bytes() {
+                    let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+                    buffer = (buffer << 5) | value;
+                    bits += 5;
+                    if bits >= 8 {
+                        bits -= 8;
+                        data.push((buffer >> bits) as u8);
+                    }
+                }
+                Some(data)
+            }
+        }
+    }
+
+    /// Get the multibase prefix character of the encoding.
+    pub fn multibase_prefix(self) -> char {
+        match self {
+            IdentityEncoding::Base58 => 'z',
+            IdentityEncoding::Hex => 'f',
+            IdentityEncoding::Base32 => 'b',
+        }
+    }
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// How identities are computed and written.
+///
+/// The default (SHA-256, Base58, not self-describing) matches `compute_identity`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdentityFormat {
+    /// Hash algorithm of the digest
+    pub algorithm: HashAlgorithm,
+
+    /// Text encoding of the identity
+    pub encoding: IdentityEncoding,
+
+    /// Write the identity as a multibase-encoded multihash, so identities computed with
+    /// different algorithms and encodings can be told apart (e.g. in a store during migration)
+    pub self_describing: bool,
+}
+
+impl IdentityFormat {
+    /// Compute the identity of a data slice.
+    pub fn compute(&self, data: &[u8]) -> String {
+        let digest = self.algorithm.digest(data);
+        if !self.self_describing {
+            return self.encoding.encode(&digest);
+        }
+
+        let mut multihash = Vec::with_capacity(digest.len() + 4);
+        write_varint(&mut multihash, self.algorithm.multihash_code());
+        write_varint(&mut multihash, digest.len() as u64);
+        multihash.extend_from_slice(&digest);
+        format!(
+            "{}{}",
+            self.encoding.multibase_prefix(),
+            self.encoding.encode(&multihash)
+        )
+    }
+
+    /// Check whether text could have been computed with this format: it decodes to a digest of
+    /// the algorithm's length and is written exactly as the encoding writes it, or, for
+    /// self-describing formats, is any self-describing identity (see `from_identity`).
+    pub fn is_identity(&self, identity: &str) -> bool {
+        if self.self_describing {
+            return IdentityFormat::from_identity(identity).is_some();
+        }
+        match self.encoding.decode(identity) {
+            Some(digest) => {
+                digest.len() == self.algorithm.digest_len()
+                    && self.encoding.encode(&digest) == identity
+            }
+            None => false,
+        }
+    }
+
+    /// Determine the format of a self-describing identity, returning `None` if the identity isn't
+    /// self-describing or uses an unknown algorithm or encoding.
+    pub fn from_identity(identity: &str) -> Option<IdentityFormat> {
+        let prefix = identity.chars().next()?;
+        let encoding = IdentityEncoding::ALL
+            .iter()
+            .cloned()
+            .find(|encoding| encoding.multibase_prefix() == prefix)?;
+        let multihash = encoding.decode(&identity[prefix.len_utf8()..])?;
+
+        let mut bytes = multihash.as_slice();
+        let code = read_varint(&mut bytes)?;
+        let length = read_varint(&mut bytes)?;
+        let algorithm = HashAlgorithm::ALL
+            .iter()
+            .cloned()
+            .find(|algorithm| algorithm.multihash_code() == code)?;
+        if length != bytes.len() as u64 || algorithm.digest_len() != bytes.len() {
+            return None;
+        }
+
+        Some(IdentityFormat {
+            algorithm,
+            encoding,
+            self_describing: true,
+        })
+    }
+}
+
+/// Write an unsigned LEB128 varint.
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+/// Read an unsigned LEB128 varint, advancing the slice past it.
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (index, byte) in data.iter().enumerate().take(9) {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            *data = &data[index + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn format(algorithm: HashAlgorithm, encoding: IdentityEncoding) -> IdentityFormat {
+        IdentityFormat {
+            algorithm,
+            encoding,
+            self_describing: false,
+        }
+    }
+
+    #[test]
+    fn sha256_hex() {
+        let format = format(HashAlgorithm::Sha256, IdentityEncoding::Hex);
+        assert_eq!(format.compute(b"abc"), SHA256_ABC);
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_hex() {
+        let format = format(HashAlgorithm::Blake3, IdentityEncoding::Hex);
+        assert_eq!(
+            format.compute(b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    #[cfg(feature = "xxh3")]
+    #[test]
+    fn xxh3_hex() {
+        let format = format(HashAlgorithm::Xxh3, IdentityEncoding::Hex);
+        assert_eq!(format.compute(b""), "2d06800538d394c2");
+    }
+
+    #[test]
+    fn base32_vectors() {
+        // RFC 4648 test vectors, lowercase and without padding.
+        let vectors = [
+            ("", ""),
+            ("f", "my"),
+            ("fo", "mzxq"),
+            ("foo", "mzxw6"),
+            ("foob", "mzxw6yq"),
+            ("fooba", "mzxw6ytb"),
+            ("foobar", "mzxw6ytboi"),
+        ];
+        for (data, text) in vectors.iter() {
+            assert_eq!(IdentityEncoding::Base32.encode(data.as_bytes()), *text);
+            assert_eq!(
+                IdentityEncoding::Base32.decode(text),
+                Some(data.as_bytes().to_vec())
+            );
+        }
+        assert_eq!(IdentityEncoding::Base32.decode("mzxw1"), None);
+        assert_eq!(IdentityEncoding::Base32.decode("MZXW6"), None);
+    }
+
+    #[test]
+    fn hex_and_base58_round_trip() {
+        let data = [0u8, 1, 0x7f, 0x80, 0xfe, 0xff];
+        assert_eq!(IdentityEncoding::Hex.encode(&data), "00017f80feff");
+        assert_eq!(
+            IdentityEncoding::Base58.encode(b"Hello World!"),
+            "2NEpo7TZRRrLZSi2U"
+        );
+        for encoding in IdentityEncoding::ALL.iter() {
+            let text = encoding.encode(&data);
+            assert_eq!(encoding.decode(&text), Some(data.to_vec()));
+        }
+        assert_eq!(IdentityEncoding::Hex.decode("abc"), None);
+        assert_eq!(IdentityEncoding::Hex.decode("zz"), None);
+        assert_eq!(IdentityEncoding::Base58.decode("0OIl"), None);
+    }
+
+    #[test]
+    fn varint() {
+        for (value, bytes) in [
+            (0u64, vec![0u8]),
+            (0x7f, vec![0x7f]),
+            (0x80, vec![0x80, 0x01]),
+            (300, vec![0xac, 0x02]),
+            (0xb3e3, vec![0xe3, 0xe7, 0x02]),
+        ] {
+            let mut data = Vec::new();
+            write_varint(&mut data, value);
+            assert_eq!(data, bytes);
+
+            data.push(0xaa);
+            let mut slice = data.as_slice();
+            assert_eq!(read_varint(&mut slice), Some(value));
+            assert_eq!(slice, [0xaa]);
+        }
+        assert_eq!(read_varint(&mut &[0x80u8, 0x80][..]), None);
+        assert_eq!(read_varint(&mut &[][..]), None);
+    }
+
+    #[test]
+    fn self_describing() {
+        let format = IdentityFormat {
+            algorithm: HashAlgorithm::Sha256,
+            encoding: IdentityEncoding::Hex,
+            self_describing: true,
+        };
+        let identity = format.compute(b"abc");
+        assert_eq!(identity, format!("f1220{}", SHA256_ABC));
+        assert_eq!(IdentityFormat::from_identity(&identity), Some(format));
+
+        for algorithm in HashAlgorithm::ALL.iter() {
+            for encoding in IdentityEncoding::ALL.iter() {
+                let format = IdentityFormat {
+                    algorithm: *algorithm,
+                    encoding: *encoding,
+                    self_describing: true,
+                };
+                let identity = format.compute(b"abc");
+                assert_eq!(IdentityFormat::from_identity(&identity), Some(format));
+            }
+        }
+    }
+
+    #[test]
+    fn from_identity_rejects_other_identities() {
+        // Plain identities, truncated digests, unknown algorithms and unknown prefixes.
+        assert_eq!(IdentityFormat::from_identity(SHA256_ABC), None);
+        assert_eq!(IdentityFormat::from_identity(""), None);
+        assert_eq!(IdentityFormat::from_identity("f"), None);
+        assert_eq!(IdentityFormat::from_identity("f1220ba78"), None);
+        assert_eq!(
+            IdentityFormat::from_identity(&format!("f1320{}", SHA256_ABC)),
+            None
+        );
+        assert_eq!(
+            IdentityFormat::from_identity(&format!("q1220{}", SHA256_ABC)),
+            None
+        );
+    }
+
+    #[test]
+    fn is_identity() {
+        for algorithm in HashAlgorithm::ALL.iter() {
+            for encoding in IdentityEncoding::ALL.iter() {
+                for self_describing in [false, true].iter() {
+                    let format = IdentityFormat {
+                        algorithm: *algorithm,
+                        encoding: *encoding,
+                        self_describing: *self_describing,
+                    };
+                    assert!(format.is_identity(&format.compute(b"abc")));
+                }
+            }
+        }
+
+        // Other text in the alphabet, the wrong length or case, and the wrong format.
+        let hex = format(HashAlgorithm::Sha256, IdentityEncoding::Hex);
+        let base58 = IdentityFormat::default();
+        assert!(!base58.is_identity("Missing"));
+        assert!(!base58.is_identity(""));
+        assert!(!hex.is_identity(&SHA256_ABC[2..]));
+        assert!(!hex.is_identity(&SHA256_ABC.to_uppercase()));
+        assert!(!base58.is_identity(SHA256_ABC));
+        assert!(!hex.is_identity(&base58.compute(b"abc")));
+        assert!(!IdentityFormat {
+            self_describing: true,
+            ..hex
+        }
+        .is_identity(SHA256_ABC));
+    }
+}
@@ -1,21 +1,57 @@
+extern crate base58;
 extern crate petgraph;
 extern crate ptree;
-#[macro_use]
-extern crate lazy_static;
-extern crate base58;
-extern crate regex;
 extern crate sha2;
 
+mod build_script;
+mod conditional;
+mod depfile;
+mod identity;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod permutation;
+mod provider;
+mod reconstruct;
+mod scan;
+mod source_map;
+mod store;
+#[cfg(feature = "watch")]
+mod watch;
+mod workspace;
+
+pub use crate::build_script::{
+    build_script, BuildScriptEntryPoint, BuildScriptOptions, BuildScriptOutput,
+};
+pub use crate::depfile::{graph_dependencies, graph_to_depfile, graph_to_rerun_if_changed};
+pub use crate::identity::{HashAlgorithm, IdentityEncoding, IdentityFormat};
+#[cfg(feature = "parallel")]
+pub use crate::parallel::{traverse_build_parallel, traverse_patch_parallel};
+pub use crate::permutation::{
+    build_permutation, build_permutations, permutation_identity, Permutation, PermutationAxis,
+    PermutationMatrix,
+};
+pub use crate::provider::{DiskSourceProvider, FileStamp, MemorySourceProvider, SourceProvider};
+pub use crate::reconstruct::{reconstruct, Reconstruction};
+pub use crate::source_map::{SourceLocation, SourceMap, SourceMapSegment};
+pub use crate::store::{ContentStore, ContentStoreOptions};
+#[cfg(feature = "watch")]
+pub use crate::watch::{WatchEvent, WorkspaceWatcher};
+pub use crate::workspace::{IncludeWorkspace, RootIdentityChange};
+
+use crate::conditional::{Conditions, MacroTable};
+use crate::scan::Directive;
+use crate::source_map::SourceMapBuilder;
+
 use encoding::label::encoding_from_whatwg_label;
 use encoding::DecoderTrap;
 use log::trace;
 use normalize_line_endings::normalized;
-use petgraph::algo::is_cyclic_directed;
 use petgraph::dot::Dot;
 use petgraph::prelude::*;
 use petgraph::visit::Walker;
 use ptree::graph::print_graph;
-use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io;
@@ -23,15 +59,16 @@ use std::io::Read;
 use std::iter::FromIterator;
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
 
 pub fn decode_data_as_utf8(byte_str: &[u8], normalize_endings: bool) -> String {
-    let result = chardet::detect(&byte_str);
+    let result = chardet::detect(byte_str);
     let encoding = chardet::charset2encoding(&result.0);
     let coder = encoding_from_whatwg_label(encoding);
-    if coder.is_some() {
+    if let Some(coder) = coder {
         let utf8_text = coder
-            .unwrap()
-            .decode(&byte_str, DecoderTrap::Ignore)
+            .decode(byte_str, DecoderTrap::Ignore)
             .expect("Error decoding utf-8 data");
         if normalize_endings {
             let normalized_text = String::from_iter(normalized(utf8_text.chars()));
@@ -59,8 +96,104 @@ pub struct Include {
 
     /// Identifies if the path is relative or absolute
     pub relative_path: bool,
+
+    /// Start position in text buffer of the path (between the delimiters)
+    pub path_range_start: usize,
+
+    /// End position in text buffer of the path (between the delimiters)
+    pub path_range_end: usize,
+
+    /// Line number (1-based) of the include directive
+    pub line: usize,
+
+    /// Column number (1-based, in bytes) of the include directive
+    pub column: usize,
+
+    /// Directory the include path was resolved against, if it was found
+    pub resolved_dir: Option<PathBuf>,
 }
 
+/// Represents one step of an include chain; a file and the directive within it that was followed.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct IncludeChainLink {
+    /// Canonical path of the including file
+    pub include_file: PathBuf,
+
+    /// Line number (1-based) of the followed include directive
+    pub line: usize,
+
+    /// Column number (1-based, in bytes) of the followed include directive
+    pub column: usize,
+}
+
+/// Represents an include directive that could not be resolved to a file.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MissingInclude {
+    /// Include path as spelled in the directive
+    pub spelled_path: PathBuf,
+
+    /// Canonical path of the file containing the directive
+    pub includer: PathBuf,
+
+    /// Line number (1-based) of the include directive
+    pub line: usize,
+
+    /// Column number (1-based, in bytes) of the include directive
+    pub column: usize,
+
+    /// Identifies if the path is relative or absolute
+    pub relative_path: bool,
+}
+
+/// Errors that can occur while building an include graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncludeError {
+    /// A file (directly or indirectly) includes itself.
+    Cycle {
+        /// Every file and directive followed from the root up to the repeated file
+        chain: Vec<IncludeChainLink>,
+
+        /// Canonical path of the file that was included a second time
+        repeated_file: PathBuf,
+    },
+
+    /// An include directive could not be resolved (only reported by strict builds).
+    Unresolved(MissingInclude),
+}
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncludeError::Cycle {
+                chain,
+                repeated_file,
+            } => {
+                write!(f, "include cycle detected: ")?;
+                for link in chain {
+                    write!(
+                        f,
+                        "{}:{}:{} -> ",
+                        link.include_file.display(),
+                        link.line,
+                        link.column
+                    )?;
+                }
+                write!(f, "{}", repeated_file.display())
+            }
+            IncludeError::Unresolved(missing) => write!(
+                f,
+                "unresolved include {:?} at {}:{}:{}",
+                missing.spelled_path,
+                missing.includer.display(),
+                missing.line,
+                missing.column
+            ),
+        }
+    }
+}
+
+impl Error for IncludeError {}
+
 /// Represents a particular include file.
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct IncludeNode {
@@ -78,16 +211,40 @@ pub struct IncludeNode {
 
     /// Resolved file contents (flattened or patched)
     pub flattened: String,
+
+    /// Mapping from `flattened` back to the original files (only set when flattening)
+    pub source_map: Option<SourceMap>,
+
+    /// Patched identity of the root combined with `PatchOptions::context`, for use as the cache
+    /// key of compiled output (only set for the root node)
+    pub salted_identity: Option<String>,
+
+    /// Decoded contents of the include file, loaded once by the graph builder and reused by the
+    /// patch and flatten passes (see `reload_changed_files`)
+    pub text: Option<Arc<str>>,
+
+    /// Line endings of `text` were normalized to Unix LF
+    pub text_normalized: bool,
 }
 
 impl fmt::Display for IncludeNodeWeight {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref missing) = self.missing {
+            return write!(
+                f,
+                "(missing:[{:#?}] f:[{:#?}:{}:{}])",
+                &missing.spelled_path,
+                &missing.includer.file_name().unwrap_or_default(),
+                missing.line,
+                missing.column
+            );
+        }
         let source_identity = match self.node.source_identity {
-            Some(ref identity) => &identity,
+            Some(ref identity) => identity,
             None => "INVALID",
         };
         let patched_identity = match self.node.patched_identity {
-            Some(ref identity) => &identity,
+            Some(ref identity) => identity,
             None => "INVALID",
         };
         write!(
@@ -109,14 +266,40 @@ impl IncludeNode {
             source_identity: None,
             patched_identity: None,
             flattened: String::new(),
+            source_map: None,
+            salted_identity: None,
+            text: None,
+            text_normalized: false,
+        }
+    }
+
+    /// Get the decoded contents of the backing file, reusing the text loaded by the graph builder
+    /// when possible and otherwise loading it from a `SourceProvider`.
+    pub fn source_text(&self, provider: &dyn SourceProvider, normalize_endings: bool) -> Arc<str> {
+        match self.text {
+            Some(ref text) if self.text_normalized == normalize_endings => text.clone(),
+            Some(ref text) if normalize_endings => {
+                Arc::from(String::from_iter(normalized(text.chars())))
+            }
+            _ => Arc::from(self.data_as_string_from(provider, normalize_endings)),
         }
     }
 
     /// Load the contents of the `IncludeNode` backing file and return as a utf8 encoded string.
     pub fn data_as_string(&self, normalize_endings: bool) -> String {
-        let data = read_file(&self.include_file);
+        self.data_as_string_from(&DiskSourceProvider, normalize_endings)
+    }
+
+    /// Load the contents of the `IncludeNode` backing file from a `SourceProvider` and return as a
+    /// utf8 encoded string.
+    pub fn data_as_string_from(
+        &self,
+        provider: &dyn SourceProvider,
+        normalize_endings: bool,
+    ) -> String {
+        let data = provider.read(&self.include_file);
         if let Ok(ref data) = data {
-            decode_data_as_utf8(&data, normalize_endings)
+            decode_data_as_utf8(data, normalize_endings)
         } else {
             String::new()
         }
@@ -132,148 +315,1003 @@ pub struct IncludeNodeWeight {
     /// Pattern matched include directives for the include file.
     pub includes: Vec<Include>,
 
-    /// Useful identifier for locating the root in raw nodes.
+    /// Include directives on inactive conditional branches, which are not followed (only
+    /// populated when `BuildOptions::defines` is set).
+    pub inactive_includes: Vec<Include>,
+
+    /// Useful identifier for locating the roots in raw nodes (see `get_root_nodes`).
     pub(crate) is_root: bool,
+
+    /// Set for placeholder nodes of include directives that could not be resolved.
+    pub missing: Option<MissingInclude>,
+}
+
+/// Options controlling how the include graph is constructed.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct BuildOptions {
+    /// Normalize line endings to Unix LF when loading files
+    pub normalize_endings: bool,
+
+    /// Additional include search paths, tried in order after the working directory
+    pub include_dirs: Vec<PathBuf>,
+
+    /// Fail with `IncludeError::Unresolved` instead of adding missing nodes to the graph
+    pub strict: bool,
+
+    /// Predefined macros (name to value) for conditional-compilation aware discovery; when set,
+    /// `#if`/`#ifdef`/`#ifndef`/`#elif`/`#else`/`#endif` are evaluated along with the `#define` and
+    /// `#undef` directives seen so far in the translation unit, and only includes on active
+    /// branches are followed
+    pub defines: Option<BTreeMap<String, String>>,
+}
+
+/// Delimiters written around a patched include path.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncludeDelimiter {
+    /// Keep the delimiters of the original include directive
+    #[default]
+    Preserve,
+
+    /// Always write `#include "path"`
+    Quoted,
+
+    /// Always write `#include <path>`
+    Angle,
+}
+
+/// Options controlling how Merkle identities are patched into include directives.
+///
+/// Only the path between the delimiters is rewritten, so spacing such as `#   include` is kept.
+/// The written path is `identity_prefix`, the identity, then `identity_suffix`; for example a
+/// prefix of `cas/` and suffix of `.hlsli` produces `#include "cas/<identity>.hlsli"`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct PatchOptions {
+    /// Normalize line endings to Unix LF when loading files
+    pub normalize_endings: bool,
+
+    /// Delimiters written around the patched path
+    pub delimiter: IncludeDelimiter,
+
+    /// Text written before the identity in the patched path
+    pub identity_prefix: String,
+
+    /// Text written after the identity in the patched path
+    pub identity_suffix: String,
+
+    /// Compiler inputs mixed into the identity of the root node (`IncludeNode::salted_identity`)
+    pub context: Option<CompileContext>,
+
+    /// Hash algorithm and encoding of the computed identities
+    pub identity: IdentityFormat,
+}
+
+/// Compiler inputs, other than the source text, that affect compiled output.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct CompileContext {
+    /// Compiler name and build (e.g. `dxc 1.8.2407`)
+    pub compiler: String,
+
+    /// Target profile (e.g. `cs_6_6`, `spirv1.5`)
+    pub target_profile: String,
+
+    /// Entry point name
+    pub entry_point: String,
+
+    /// Compiler flags (e.g. `-O3`), in the order they are passed
+    pub flags: Vec<String>,
+}
+
+impl CompileContext {
+    /// Combine an identity with the compile context.
+    ///
+    /// The data hashed is domain separated, so a salted identity can't collide with the identity
+    /// of file contents.
+    pub fn salted_identity(&self, identity: &str, format: &IdentityFormat) -> String {
+        let fields = [
+            identity,
+            &self.compiler,
+            &self.target_profile,
+            &self.entry_point,
+        ];
+        let flags = self.flags.iter().map(String::as_str);
+        compute_domain_identity(
+            format,
+            "compile-context",
+            fields.iter().cloned().chain(flags),
+        )
+    }
+}
+
+impl PatchOptions {
+    /// Get the include path written for a Merkle identity.
+    pub fn identity_path(&self, identity: &str) -> String {
+        format!(
+            "{}{}{}",
+            self.identity_prefix, identity, self.identity_suffix
+        )
+    }
+
+    /// Get the Merkle identity referenced by an include path written by `identity_path`, or `None`
+    /// if the path isn't in identity form (such as an include that wasn't patched); the identity
+    /// must be valid for `identity` (see `IdentityFormat::is_identity`).
+    pub fn identity_from_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let identity = path
+            .strip_prefix(self.identity_prefix.as_str())?
+            .strip_suffix(self.identity_suffix.as_str())?;
+        if !self.identity.is_identity(identity) {
+            return None;
+        }
+        Some(identity)
+    }
+
+    /// Rewrite an include directive in `text` to reference a Merkle identity.
+    fn patch_include(&self, text: &mut String, include: &Include, identity: &str) {
+        let path = self.identity_path(identity);
+        let (open, close) = match self.delimiter {
+            IncludeDelimiter::Preserve => {
+                text.replace_range(include.path_range_start..include.path_range_end, &path);
+                return;
+            }
+            IncludeDelimiter::Quoted => ('"', '"'),
+            IncludeDelimiter::Angle => ('<', '>'),
+        };
+        let patch = format!("{}{}{}", open, path, close);
+        text.replace_range(include.path_range_start - 1..include.range_end, &patch);
+    }
+}
+
+/// Syntax of the `#line` directives emitted when flattening.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineDirectiveStyle {
+    /// Don't emit `#line` directives
+    #[default]
+    None,
+
+    /// HLSL and C style `#line N "path"`
+    C,
+
+    /// GLSL style `#line N S`, where `S` is the source string number of the file (its index in
+    /// the list returned by `traverse_flatten_with`)
+    Glsl,
+}
+
+/// Options controlling how include directives are flattened.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct FlattenOptions {
+    /// Normalize line endings to Unix LF when loading files
+    pub normalize_endings: bool,
+
+    /// Emit `#line` directives at the start of each embedded file, and after each embedded file
+    /// returns to its includer, so compiler diagnostics refer to the original sources
+    pub line_directives: LineDirectiveStyle,
+
+    /// Embed files that contain `#pragma once`, or are wrapped in an include guard, only once per
+    /// translation unit; later includes of such a file are removed, as are `#pragma once`
+    /// directives
+    pub include_once: bool,
+
+    /// Hash algorithm and encoding of the computed identities
+    pub identity: IdentityFormat,
+}
+
+impl FlattenOptions {
+    /// Get the `#line` directive for a line of a source file, if enabled.
+    fn line_directive(
+        &self,
+        line: usize,
+        include_file: &Path,
+        source_files: &[PathBuf],
+    ) -> Option<String> {
+        match self.line_directives {
+            LineDirectiveStyle::None => None,
+            LineDirectiveStyle::C => {
+                let path = include_file
+                    .to_string_lossy()
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"");
+                Some(format!("#line {} \"{}\"", line, path))
+            }
+            LineDirectiveStyle::Glsl => {
+                let source = source_files
+                    .iter()
+                    .position(|source_file| source_file == include_file)
+                    .unwrap_or_default();
+                Some(format!("#line {} {}", line, source))
+            }
+        }
+    }
 }
 
 pub type IncludeNodeLevel = u32;
 pub type IncludeNodeGraph = Graph<IncludeNodeWeight, IncludeNodeLevel>;
 
 /// Compute a Sha256 + Base58 encoded identity for a data slice.
+///
+/// See `IdentityFormat` for other hash algorithms and encodings.
 pub fn compute_identity(data: &[u8]) -> String {
-    use base58::ToBase58;
-    use sha2::{Digest, Sha256};
-
-    // Create a Sha256 object
-    let mut hasher = Sha256::default();
-
-    // Write input data
-    hasher.input(data);
+    IdentityFormat::default().compute(data)
+}
 
-    // Read hash digest and consume hasher
-    hasher.result().to_base58()
+/// Compute an identity for a list of fields, separated from file contents and other domains by a
+/// domain tag; each field is length prefixed so that fields can't run into each other.
+pub(crate) fn compute_domain_identity<'a, I: IntoIterator<Item = &'a str>>(
+    format: &IdentityFormat,
+    domain: &str,
+    fields: I,
+) -> String {
+    let mut data = format!("include-merkle:{}\0", domain).into_bytes();
+    for field in fields {
+        data.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        data.extend_from_slice(field.as_bytes());
+    }
+    format.compute(&data)
 }
 
 /// Traverse the graph in order to construct the structure and meta data.
+///
+/// Panics if an include cycle is encountered, see `try_traverse_build` for a fallible version.
 pub fn traverse_build(
-    mut graph: &mut IncludeNodeGraph,
+    graph: &mut IncludeNodeGraph,
     working_dir: &Path,
     include_file: &Path,
     level: IncludeNodeLevel,
     normalize_endings: bool,
 ) -> NodeIndex {
-    let include_dir = include_file.parent().unwrap();
-    let include_node = IncludeNode::new(&working_dir, &include_file);
-    let include_text = include_node.data_as_string(normalize_endings);
+    try_traverse_build(graph, working_dir, include_file, level, normalize_endings)
+        .unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Traverse the graph in order to construct the structure and meta data, reporting include
+/// cycles as an `IncludeError` instead of panicking; the graph is left unchanged on error.
+pub fn try_traverse_build(
+    graph: &mut IncludeNodeGraph,
+    working_dir: &Path,
+    include_file: &Path,
+    level: IncludeNodeLevel,
+    normalize_endings: bool,
+) -> Result<NodeIndex, IncludeError> {
+    let options = BuildOptions {
+        normalize_endings,
+        ..Default::default()
+    };
+    traverse_build_with(
+        graph,
+        working_dir,
+        include_file,
+        level,
+        &options,
+        &DiskSourceProvider,
+    )
+}
+
+/// Traverse the graph in order to construct the structure and meta data, using the specified
+/// build options and reading files through a `SourceProvider`.
+///
+/// Each file is keyed by its canonical path, so a header included from several places (or one
+/// that already exists in `graph`) becomes a single node with multiple incoming edges. On error,
+/// every node and edge added by the build is removed again, leaving the graph unchanged.
+pub fn traverse_build_with(
+    graph: &mut IncludeNodeGraph,
+    working_dir: &Path,
+    include_file: &Path,
+    level: IncludeNodeLevel,
+    options: &BuildOptions,
+    provider: &dyn SourceProvider,
+) -> Result<NodeIndex, IncludeError> {
+    let include_file = provider
+        .canonicalize(include_file)
+        .unwrap_or_else(|| include_file.to_path_buf());
+    build_graph(
+        graph,
+        working_dir,
+        &include_file,
+        level,
+        options,
+        provider,
+        &mut HashMap::new(),
+    )
+}
+
+/// A file loaded ahead of time, to be taken by the graph builder.
+pub(crate) struct PrefetchedFile {
+    /// Decoded text of the file
+    pub text: String,
+
+    /// Include directives of the text when already parsed, each as parsed and as resolved with
+    /// only the directory of the file as a quoted include directory (the includers of the file
+    /// aren't known ahead of time)
+    pub includes: Option<Vec<(Include, Include)>>,
+}
+
+/// Build the graph for a canonical root path, using (and taking) any files that were loaded ahead
+/// of time. The graph is left unchanged on error.
+pub(crate) fn build_graph(
+    graph: &mut IncludeNodeGraph,
+    working_dir: &Path,
+    include_file: &Path,
+    level: IncludeNodeLevel,
+    options: &BuildOptions,
+    provider: &dyn SourceProvider,
+    prefetched: &mut HashMap<PathBuf, PrefetchedFile>,
+) -> Result<NodeIndex, IncludeError> {
+    let nodes = graph
+        .node_indices()
+        .filter(|node_index| graph[*node_index].missing.is_none())
+        .map(|node_index| (graph[node_index].node.include_file.clone(), node_index))
+        .collect::<HashMap<PathBuf, NodeIndex>>();
+    let search_dirs = std::iter::once(working_dir.to_path_buf())
+        .chain(options.include_dirs.iter().cloned())
+        .collect();
+    let rollback = Rollback {
+        node_count: graph.node_count(),
+        edge_count: graph.edge_count(),
+        ..Default::default()
+    };
+    let mut builder = GraphBuilder {
+        graph,
+        working_dir,
+        options,
+        provider,
+        search_dirs,
+        nodes,
+        chain: Vec::new(),
+        macros: options.defines.as_ref().map(MacroTable::new),
+        sources: HashMap::new(),
+        prefetched,
+        rollback,
+    };
+    match builder.build_node(include_file, level) {
+        Ok(root_node) => {
+            graph[root_node].is_root = true;
+            Ok(root_node)
+        }
+        Err(error) => {
+            // Nodes left behind would be reused (without their missing edges) by later builds.
+            let rollback = std::mem::take(&mut builder.rollback);
+            rollback.undo(graph);
+            Err(error)
+        }
+    }
+}
+
+/// Files found to have changed by `reload_changed_files`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ReloadedFiles {
+    /// Nodes whose text changed; their cached text and include directive ranges were updated
+    pub changed: Vec<NodeIndex>,
+
+    /// Changed nodes whose include directives no longer reference the same files (or, with
+    /// conditional discovery, may now take different branches). The include directives of nodes
+    /// that reference different files are cleared, as their ranges no longer match the text, so
+    /// they are neither patched nor flattened; build a new graph with `traverse_build_with` for
+    /// the change to take effect (building into the same graph reuses the existing nodes)
+    pub restructured: Vec<NodeIndex>,
+}
+
+/// Re-read every file in the graph, updating the cached text (`IncludeNode::text`) and
+/// re-parsing the include directives of the files that changed.
+pub fn reload_changed_files(
+    graph: &mut IncludeNodeGraph,
+    options: &BuildOptions,
+    provider: &dyn SourceProvider,
+) -> ReloadedFiles {
+    let mut reloaded = ReloadedFiles::default();
+    let node_indices = graph.node_indices().collect::<Vec<NodeIndex>>();
+    for node_index in node_indices {
+        if let Some(restructured) = reload_node(graph, node_index, options, provider) {
+            if restructured {
+                reloaded.restructured.push(node_index);
+            }
+            reloaded.changed.push(node_index);
+        }
+    }
+    reloaded
+}
+
+/// Re-read the file of a node, returning `None` if its text is unchanged, and otherwise whether
+/// the node was restructured (see `ReloadedFiles::restructured`).
+pub(crate) fn reload_node(
+    graph: &mut IncludeNodeGraph,
+    node_index: NodeIndex,
+    options: &BuildOptions,
+    provider: &dyn SourceProvider,
+) -> Option<bool> {
+    let node = &graph[node_index].node;
+    if graph[node_index].missing.is_some() {
+        return None;
+    }
+    let include_text = node.data_as_string_from(provider, options.normalize_endings);
+    if node.text.as_deref() == Some(include_text.as_str())
+        && node.text_normalized == options.normalize_endings
+    {
+        return None;
+    }
+
+    // Quoted includes are looked up next to the file, then next to each of its includers.
+    let mut quoted_dirs: Vec<PathBuf> = Vec::new();
+    let includer_files = graph
+        .neighbors_directed(node_index, Direction::Incoming)
+        .map(|includer| graph[includer].node.include_file.as_path());
+    for include_dir in std::iter::once(node.include_file.as_path())
+        .chain(includer_files)
+        .filter_map(Path::parent)
+    {
+        if !quoted_dirs.iter().any(|dir| dir == include_dir) {
+            quoted_dirs.push(include_dir.to_path_buf());
+        }
+    }
+    let search_dirs = std::iter::once(node.working_dir.clone())
+        .chain(options.include_dirs.iter().cloned())
+        .collect::<Vec<PathBuf>>();
+    let includes = resolve_includes_in(&include_text, &quoted_dirs, &search_dirs, provider);
+
+    let weight = &mut graph[node_index];
+    weight.node.text = Some(Arc::from(include_text));
+    weight.node.text_normalized = options.normalize_endings;
+
+    // Directive ranges can be updated in place as long as they still include the same files.
+    let mut previous = weight
+        .includes
+        .iter()
+        .map(|include| (include, true))
+        .chain(
+            weight
+                .inactive_includes
+                .iter()
+                .map(|include| (include, false)),
+        )
+        .collect::<Vec<(&Include, bool)>>();
+    previous.sort_by_key(|(include, _)| include.range_start);
+    let same_files = previous.len() == includes.len()
+        && previous
+            .iter()
+            .zip(&includes)
+            .all(|((previous, _), include)| {
+                previous.include_path == include.include_path
+                    && previous.resolved_dir == include.resolved_dir
+            });
+    if same_files {
+        let active = previous
+            .iter()
+            .map(|(_, active)| *active)
+            .collect::<Vec<bool>>();
+        let (active, inactive): (Vec<_>, Vec<_>) = includes
+            .into_iter()
+            .zip(active)
+            .partition(|(_, active)| *active);
+        weight.includes = active.into_iter().map(|(include, _)| include).collect();
+        weight.inactive_includes = inactive.into_iter().map(|(include, _)| include).collect();
+    } else {
+        // Stale ranges would splice identities into the wrong place of the new text.
+        weight.includes.clear();
+        weight.inactive_includes.clear();
+    }
+    Some(!same_files || options.defines.is_some())
+}
+
+/// Shared state while recursively building the include graph.
+struct GraphBuilder<'a> {
+    graph: &'a mut IncludeNodeGraph,
+    working_dir: &'a Path,
+    options: &'a BuildOptions,
+    provider: &'a dyn SourceProvider,
+
+    /// Include search paths; the working directory followed by `BuildOptions::include_dirs`.
+    search_dirs: Vec<PathBuf>,
+
+    /// Canonical path of every file already in the graph.
+    nodes: HashMap<PathBuf, NodeIndex>,
+
+    /// Include directives followed from the root to the file currently being built.
+    chain: Vec<IncludeChainLink>,
+
+    /// Macros defined so far in the translation unit, when evaluating conditionals.
+    macros: Option<MacroTable>,
+
+    /// Directives of every file, when evaluating conditionals.
+    sources: HashMap<NodeIndex, Rc<ConditionalSource>>,
 
-    // Parse include text and extract all includes.
-    let includes = resolve_includes(&include_text, &working_dir, &include_dir);
+    /// Files loaded ahead of time (by the parallel builder, or kept from a previous build).
+    prefetched: &'a mut HashMap<PathBuf, PrefetchedFile>,
 
-    let (graph_node, outgoing_nodes) = if graph.node_count() == 0 {
-        // Borrowing rules mean we can't add the root node first and share `includes`. Lets only
-        // clone one extra time at least (not every iteration).
-        let graph_node = graph.add_node(IncludeNodeWeight {
+    /// Changes to undo if the build fails.
+    rollback: Rollback,
+}
+
+/// The state of a graph before a build, to restore if the build fails.
+#[derive(Default)]
+struct Rollback {
+    /// Number of nodes in the graph before the build; later nodes were added by it.
+    node_count: usize,
+
+    /// Number of edges in the graph before the build; later edges were added by it.
+    edge_count: usize,
+
+    /// Original active and inactive include directives of nodes from earlier builds whose
+    /// conditional includes were activated.
+    includes: HashMap<NodeIndex, (Vec<Include>, Vec<Include>)>,
+
+    /// Original levels of edges from earlier builds that were updated.
+    levels: HashMap<EdgeIndex, IncludeNodeLevel>,
+}
+
+impl Rollback {
+    /// Remove every node and edge added by the build and restore the nodes it changed.
+    fn undo(self, graph: &mut IncludeNodeGraph) {
+        // Removing the last edge or node never moves another one to a new index.
+        while graph.edge_count() > self.edge_count {
+            graph.remove_edge(EdgeIndex::new(graph.edge_count() - 1));
+        }
+        while graph.node_count() > self.node_count {
+            graph.remove_node(NodeIndex::new(graph.node_count() - 1));
+        }
+        for (node_index, (includes, inactive_includes)) in self.includes {
+            graph[node_index].includes = includes;
+            graph[node_index].inactive_includes = inactive_includes;
+        }
+        for (edge_index, level) in self.levels {
+            graph[edge_index] = level;
+        }
+    }
+}
+
+/// The include and conditional directives of a file, walked each time the file is included.
+struct ConditionalSource {
+    includes: Vec<Include>,
+    directives: Vec<Directive>,
+}
+
+impl<'a> GraphBuilder<'a> {
+    fn build_node(
+        &mut self,
+        include_file: &Path,
+        level: IncludeNodeLevel,
+    ) -> Result<NodeIndex, IncludeError> {
+        if let Some(graph_node) = self.nodes.get(include_file).cloned() {
+            // Every inclusion is evaluated with the macros defined at that point.
+            if self.macros.is_some() {
+                self.build_conditional_node(graph_node, include_file, level)?;
+            }
+            return Ok(graph_node);
+        }
+
+        let mut include_node = IncludeNode::new(self.working_dir, include_file);
+        let (include_text, includes) = match self.prefetched.remove(include_file) {
+            Some(PrefetchedFile {
+                text,
+                includes: Some(includes),
+            }) => (text, self.finish_includes(includes, include_file)),
+            Some(PrefetchedFile {
+                text,
+                includes: None,
+            }) => {
+                let includes = self.resolve_includes(&text, include_file);
+                (text, includes)
+            }
+            None => {
+                let text =
+                    include_node.data_as_string_from(self.provider, self.options.normalize_endings);
+                let includes = self.resolve_includes(&text, include_file);
+                (text, includes)
+            }
+        };
+        include_node.text = Some(Arc::from(include_text.as_str()));
+        include_node.text_normalized = self.options.normalize_endings;
+
+        let graph_node = self.graph.add_node(IncludeNodeWeight {
             node: include_node,
-            includes: includes.clone(),
-            is_root: true,
+            includes: Vec::new(),
+            inactive_includes: Vec::new(),
+            is_root: false,
+            missing: None,
         });
+        self.nodes.insert(include_file.to_path_buf(), graph_node);
+
+        if self.macros.is_some() {
+            self.graph[graph_node].inactive_includes = includes.clone();
+            self.sources.insert(
+                graph_node,
+                Rc::new(ConditionalSource {
+                    includes,
+                    directives: scan::scan_directives(&include_text),
+                }),
+            );
+            self.build_conditional_node(graph_node, include_file, level)?;
+        } else {
+            self.graph[graph_node].includes = includes.clone();
+            for include in &includes {
+                self.follow_include(graph_node, include_file, include, level)?;
+            }
+        }
+
+        Ok(graph_node)
+    }
+
+    /// Walk the directives of a file, following the include directives on active branches.
+    fn build_conditional_node(
+        &mut self,
+        graph_node: NodeIndex,
+        include_file: &Path,
+        level: IncludeNodeLevel,
+    ) -> Result<(), IncludeError> {
+        let source = match self.sources.get(&graph_node) {
+            Some(source) => source.clone(),
+            None => {
+                // The node was added to the graph by an earlier build.
+                let include_text = self.graph[graph_node]
+                    .node
+                    .source_text(self.provider, self.options.normalize_endings);
+                let source = Rc::new(ConditionalSource {
+                    includes: self.resolve_includes(&include_text, include_file),
+                    directives: scan::scan_directives(&include_text),
+                });
+                self.sources.insert(graph_node, source.clone());
+                source
+            }
+        };
 
-        let outgoing_nodes = includes
+        let mut conditions = Conditions::new();
+        for directive in &source.directives {
+            if directive.name != "include" {
+                if let Some(ref mut macros) = self.macros {
+                    conditions.apply(directive, macros);
+                }
+                continue;
+            }
+
+            let include = source
+                .includes
+                .iter()
+                .find(|include| include.range_start == directive.range_start);
+            let include = match include {
+                Some(include) if conditions.is_active() => include,
+                _ => continue,
+            };
+
+            // A file that is already being included further up the chain is only a cycle if it
+            // has active includes this time; mutually including headers stop at their guards.
+            let reentered = include.include_path == include_file
+                || self
+                    .chain
+                    .iter()
+                    .any(|link| link.include_file == include.include_path);
+            if reentered && !self.reenter_conditional_node(&include.include_path) {
+                continue;
+            }
+
+            // An include becomes active the first time any inclusion of the file reaches it.
+            let weight = &mut self.graph[graph_node];
+            if graph_node.index() < self.rollback.node_count {
+                self.rollback
+                    .includes
+                    .entry(graph_node)
+                    .or_insert_with(|| (weight.includes.clone(), weight.inactive_includes.clone()));
+            }
+            let inactive = weight
+                .inactive_includes
+                .iter()
+                .position(|inactive| inactive.range_start == include.range_start);
+            if let Some(inactive) = inactive {
+                let include = weight.inactive_includes.remove(inactive);
+                let position = weight
+                    .includes
+                    .partition_point(|active| active.range_start < include.range_start);
+                weight.includes.insert(position, include);
+            } else if include.resolved_dir.is_none() {
+                // Already added to the graph as a missing node.
+                continue;
+            }
+            self.follow_include(graph_node, include_file, include, level)?;
+        }
+
+        Ok(())
+    }
+
+    /// Walk the directives of a file that is already being included further up the chain,
+    /// returning whether it has an include directive on an active branch (i.e. including it again
+    /// would recurse).
+    fn reenter_conditional_node(&mut self, include_file: &Path) -> bool {
+        let source = match self.nodes.get(include_file) {
+            Some(graph_node) => self.sources[graph_node].clone(),
+            None => return true,
+        };
+        let macros = match self.macros {
+            Some(ref mut macros) => macros,
+            None => return true,
+        };
+
+        let mut conditions = Conditions::new();
+        for directive in &source.directives {
+            if directive.name != "include" {
+                conditions.apply(directive, macros);
+            } else if conditions.is_active() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Resolve the include directives of a file.
+    fn resolve_includes(&self, include_text: &str, include_file: &Path) -> Vec<Include> {
+        // Parse include text and extract all includes.
+        let quoted_dirs = self.quoted_dirs(include_file);
+        resolve_includes_in(include_text, &quoted_dirs, &self.search_dirs, self.provider)
+    }
+
+    /// Finish resolving include directives resolved ahead of time, looking up quoted includes
+    /// that weren't found next to the file in the directories of its includers, which are
+    /// searched before the include search paths.
+    fn finish_includes(
+        &self,
+        includes: Vec<(Include, Include)>,
+        include_file: &Path,
+    ) -> Vec<Include> {
+        let quoted_dirs = self.quoted_dirs(include_file);
+        let includer_dirs = quoted_dirs
             .iter()
-            .map(|ref include| {
-                traverse_build(
-                    &mut graph,
-                    &working_dir,
-                    &include.include_path,
-                    level + 1,
-                    normalize_endings,
-                )
+            .any(|dir| Some(dir.as_path()) != include_file.parent());
+        includes
+            .into_iter()
+            .map(|(mut parsed, resolved)| {
+                if !includer_dirs
+                    || !resolved.relative_path
+                    || resolved.resolved_dir.as_deref() == include_file.parent()
+                {
+                    return resolved;
+                }
+                resolve_include(&mut parsed, &quoted_dirs, &self.search_dirs, self.provider);
+                parsed
             })
-            .collect::<Vec<NodeIndex>>();
+            .collect()
+    }
 
-        (graph_node, outgoing_nodes)
-    } else {
-        let outgoing_nodes = includes
+    /// Get the directories quoted includes of a file are looked up in.
+    fn quoted_dirs(&self, include_file: &Path) -> Vec<PathBuf> {
+        // Quoted includes are looked up next to the file, then next to each of its includers.
+        let mut quoted_dirs: Vec<PathBuf> = Vec::new();
+        let includer_files = self
+            .chain
             .iter()
-            .map(|ref include| {
-                traverse_build(
-                    &mut graph,
-                    &working_dir,
-                    &include.include_path,
-                    level + 1,
-                    normalize_endings,
-                )
-            })
-            .collect::<Vec<NodeIndex>>();
+            .rev()
+            .map(|link| link.include_file.as_path());
+        for include_dir in std::iter::once(include_file)
+            .chain(includer_files)
+            .filter_map(Path::parent)
+        {
+            if !quoted_dirs.iter().any(|dir| dir == include_dir) {
+                quoted_dirs.push(include_dir.to_path_buf());
+            }
+        }
+        quoted_dirs
+    }
 
-        let graph_node = graph.add_node(IncludeNodeWeight {
-            node: include_node,
-            includes,
-            is_root: false,
-        });
+    /// Follow an include directive, keeping track of the chain from the root so that a recursive
+    /// include can be reported (rather than recursing forever).
+    fn follow_include(
+        &mut self,
+        graph_node: NodeIndex,
+        include_file: &Path,
+        include: &Include,
+        level: IncludeNodeLevel,
+    ) -> Result<(), IncludeError> {
+        if include.resolved_dir.is_none() {
+            let missing = MissingInclude {
+                spelled_path: include.include_path.clone(),
+                includer: include_file.to_path_buf(),
+                line: include.line,
+                column: include.column,
+                relative_path: include.relative_path,
+            };
+            if self.options.strict {
+                return Err(IncludeError::Unresolved(missing));
+            }
 
-        (graph_node, outgoing_nodes)
-    };
+            // Keep unresolved directives visible in the graph rather than dropping them.
+            let missing_node = self.graph.add_node(IncludeNodeWeight {
+                node: IncludeNode::new(self.working_dir, &include.include_path),
+                includes: Vec::new(),
+                inactive_includes: Vec::new(),
+                is_root: false,
+                missing: Some(missing),
+            });
+            self.graph.add_edge(graph_node, missing_node, level);
+            return Ok(());
+        }
 
-    // Create all edges, and add them to the graph.
-    outgoing_nodes.iter().for_each(|outgoing_node| {
-        let edge = graph.add_edge(graph_node, *outgoing_node, level);
-        // TODO: Cycles are currently unsupported!
-        assert!(!is_cyclic_directed(&*graph));
-        // This might work (untested):
-        if is_cyclic_directed(&*graph) {
-            graph.remove_edge(edge);
+        self.chain.push(IncludeChainLink {
+            include_file: include_file.to_path_buf(),
+            line: include.line,
+            column: include.column,
+        });
+        if self
+            .chain
+            .iter()
+            .any(|link| link.include_file == include.include_path)
+        {
+            return Err(IncludeError::Cycle {
+                chain: self.chain.clone(),
+                repeated_file: include.include_path.clone(),
+            });
         }
-    });
+        let outgoing_node = self.build_node(&include.include_path, level + 1)?;
+        self.chain.pop();
 
-    graph_node
+        // A file included more than once by the same parent still only gets a single edge.
+        if let Some(edge_index) = self.graph.find_edge(graph_node, outgoing_node) {
+            if edge_index.index() < self.rollback.edge_count {
+                let previous = self.graph[edge_index];
+                self.rollback.levels.entry(edge_index).or_insert(previous);
+            }
+        }
+        self.graph.update_edge(graph_node, outgoing_node, level);
+        Ok(())
+    }
 }
 
 /// Traverse the graph in order to patch in Merkle identities for all include directives.
 pub fn traverse_patch(graph: &mut IncludeNodeGraph, root_node: NodeIndex, normalize_endings: bool) {
+    let options = PatchOptions {
+        normalize_endings,
+        ..Default::default()
+    };
+    traverse_patch_with(graph, root_node, &options, &DiskSourceProvider)
+}
+
+/// Traverse the graph in order to patch in Merkle identities for all include directives, using
+/// the specified patch options and reading files through a `SourceProvider`.
+pub fn traverse_patch_with(
+    graph: &mut IncludeNodeGraph,
+    root_node: NodeIndex,
+    options: &PatchOptions,
+    provider: &dyn SourceProvider,
+) {
+    patch_graph(graph, &[root_node], options, provider, None)
+}
+
+/// Patched text of a file, keyed by its path and the identity patched into each of its include
+/// directives, so that graphs sharing unchanged subgraphs (such as permutations of a shader) only
+/// patch them once.
+pub(crate) type PatchCache = HashMap<(PathBuf, Vec<(usize, String)>), PatchedText>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct PatchedText {
+    source_identity: String,
+    patched_identity: String,
+    text: String,
+}
+
+/// Patch in Merkle identities for all include directives reachable from a set of roots, reusing
+/// and filling a `PatchCache`.
+pub(crate) fn patch_graph(
+    graph: &mut IncludeNodeGraph,
+    root_nodes: &[NodeIndex],
+    options: &PatchOptions,
+    provider: &dyn SourceProvider,
+    mut cache: Option<&mut PatchCache>,
+) {
     // Visit nodes in a depth-first search, emitting nodes in post-order.
     // We want to evaluate data starting at the leaf nodes (no include directives).
-    let dfs_nodes = DfsPostOrder::new(&*graph, root_node)
-        .iter(&*graph)
-        .collect::<Vec<NodeIndex>>();
-    dfs_nodes.iter().for_each(|node_index| {
-        let neighbors = graph
-            .neighbors_directed(*node_index, Direction::Outgoing)
-            .map(|neighbor| {
-                let neighbor_weight = &graph[neighbor];
-                // All neighbors should have identities at this point
-                assert!(neighbor_weight.node.source_identity.is_some());
-                assert!(neighbor_weight.node.patched_identity.is_some());
-                (
-                    neighbor_weight.node.include_file.clone(),
-                    neighbor_weight
-                        .node
-                        .patched_identity
-                        .as_ref()
-                        .unwrap()
-                        .clone(),
-                )
-            })
-            .collect::<Vec<(PathBuf, String)>>();
-
-        if let Some(ref mut node_weight) = graph.node_weight_mut(*node_index) {
-            let mut node = &mut node_weight.node;
-            let mut include_text = node.data_as_string(normalize_endings);
-            node.source_identity = Some(compute_identity(&include_text.as_bytes()));
-            for (ref include_file, ref patched_identity) in neighbors {
-                if let Some(ref include) = node_weight
-                    .includes
-                    .iter()
-                    .find(|&include| &include.include_path == include_file)
-                {
-                    let patch = format!("#include \"{}\"", patched_identity);
-                    include_text.replace_range(include.range_start..include.range_end, &patch);
-                }
+    // Nodes shared between roots are only visited once.
+    let mut dfs_nodes = Vec::new();
+    let mut dfs = DfsPostOrder::empty(&*graph);
+    for root_node in root_nodes {
+        dfs.move_to(*root_node);
+        while let Some(node_index) = dfs.next(&*graph) {
+            dfs_nodes.push(node_index);
+        }
+    }
+    for node_index in dfs_nodes {
+        if graph[node_index].missing.is_some() {
+            continue;
+        }
+
+        let identities = include_identities(graph, node_index);
+        let node_weight = &graph[node_index];
+        let key = (
+            node_weight.node.include_file.clone(),
+            node_weight
+                .includes
+                .iter()
+                .filter_map(|include| {
+                    identities
+                        .get(&include.include_path)
+                        .map(|identity| (include.range_start, identity.clone()))
+                })
+                .collect::<Vec<(usize, String)>>(),
+        );
+        let cached = cache.as_ref().and_then(|cache| cache.get(&key)).cloned();
+        let patched = cached.unwrap_or_else(|| {
+            let patched = patch_node(node_weight, &identities, options, provider);
+            if let Some(ref mut cache) = cache {
+                cache.insert(key, patched.clone());
             }
-            node.patched_identity = Some(compute_identity(&include_text.as_bytes()));
-            node.flattened = include_text;
+            patched
+        });
+        patched.store(&mut graph[node_index].node);
+    }
+
+    for root_node in root_nodes {
+        salt_root(graph, *root_node, options);
+    }
+}
+
+/// Get the patched identity of each file included by a node.
+pub(crate) fn include_identities(
+    graph: &IncludeNodeGraph,
+    node_index: NodeIndex,
+) -> HashMap<PathBuf, String> {
+    graph
+        .neighbors_directed(node_index, Direction::Outgoing)
+        .filter(|neighbor| graph[*neighbor].missing.is_none())
+        .map(|neighbor| {
+            let neighbor_weight = &graph[neighbor];
+            // All neighbors should have identities at this point
+            assert!(neighbor_weight.node.source_identity.is_some());
+            assert!(neighbor_weight.node.patched_identity.is_some());
+            (
+                neighbor_weight.node.include_file.clone(),
+                neighbor_weight
+                    .node
+                    .patched_identity
+                    .as_ref()
+                    .unwrap()
+                    .clone(),
+            )
+        })
+        .collect::<HashMap<PathBuf, String>>()
+}
+
+/// Patch the include directives of a file with the identities of the files it includes.
+pub(crate) fn patch_node(
+    node_weight: &IncludeNodeWeight,
+    identities: &HashMap<PathBuf, String>,
+    options: &PatchOptions,
+    provider: &dyn SourceProvider,
+) -> PatchedText {
+    let mut include_text = node_weight
+        .node
+        .source_text(provider, options.normalize_endings)
+        .to_string();
+    let source_identity = options.identity.compute(include_text.as_bytes());
+    // Includes are sorted by position; patch back-to-front so that rewriting a directive doesn't
+    // invalidate the ranges of the directives before it.
+    for include in node_weight.includes.iter().rev() {
+        if let Some(patched_identity) = identities.get(&include.include_path) {
+            options.patch_include(&mut include_text, include, patched_identity);
         }
-    });
+    }
+    PatchedText {
+        source_identity,
+        patched_identity: options.identity.compute(include_text.as_bytes()),
+        text: include_text,
+    }
+}
+
+impl PatchedText {
+    /// Store the patched text and identities on a node.
+    pub(crate) fn store(self, node: &mut IncludeNode) {
+        node.source_identity = Some(self.source_identity);
+        node.patched_identity = Some(self.patched_identity);
+        node.flattened = self.text;
+        node.source_map = None;
+        node.salted_identity = None;
+    }
+}
+
+/// Mix the compile context into the identity of the root node.
+pub(crate) fn salt_root(
+    graph: &mut IncludeNodeGraph,
+    root_node: NodeIndex,
+    options: &PatchOptions,
+) {
+    // Only the root is salted, so included files keep identities shared across contexts.
+    if let Some(ref context) = options.context {
+        let node = &mut graph[root_node].node;
+        node.salted_identity = node
+            .patched_identity
+            .as_ref()
+            .map(|identity| context.salted_identity(identity, &options.identity));
+    }
 }
 
 /// Traverse the graph in order to flatten the text for the root node.
@@ -282,44 +1320,309 @@ pub fn traverse_flatten(
     root_node: NodeIndex,
     normalize_endings: bool,
 ) {
+    let options = FlattenOptions {
+        normalize_endings,
+        ..Default::default()
+    };
+    traverse_flatten_with(graph, root_node, &options, &DiskSourceProvider);
+}
+
+/// Traverse the graph in order to flatten the text for the root node, using the specified
+/// flatten options and reading files through a `SourceProvider`.
+///
+/// Each flattened node also records a `SourceMap` from its text back to the original files.
+/// Returns every file reachable from the root in include order; the index of a file in this list
+/// is its source string number for `LineDirectiveStyle::Glsl`.
+pub fn traverse_flatten_with(
+    graph: &mut IncludeNodeGraph,
+    root_node: NodeIndex,
+    options: &FlattenOptions,
+    provider: &dyn SourceProvider,
+) -> Vec<PathBuf> {
+    let source_files = graph_source_files(graph, root_node);
+
     // Visit nodes in a depth-first search, emitting nodes in post-order.
-    // We want to evaluate data starting at the leaf nodes (no include directives).
     let dfs_nodes = DfsPostOrder::new(&*graph, root_node)
         .iter(&*graph)
+        .filter(|node_index| graph[*node_index].missing.is_none())
         .collect::<Vec<NodeIndex>>();
-    dfs_nodes.iter().for_each(|node_index| {
+
+    // Every node is flattened as its own translation unit, which expands its includes again, so
+    // get the text of each file once up front.
+    let sources = dfs_nodes
+        .iter()
+        .map(|node_index| {
+            let text = graph[*node_index]
+                .node
+                .source_text(provider, options.normalize_endings);
+            (*node_index, FlattenSource::new(text))
+        })
+        .collect::<HashMap<NodeIndex, FlattenSource>>();
+
+    for node_index in &dfs_nodes {
+        let mut flattener = Flattener {
+            graph,
+            sources: &sources,
+            options,
+            source_files: &source_files,
+            embedded: HashSet::new(),
+            builder: SourceMapBuilder::new(),
+        };
+        flattener.expand(*node_index);
+        let (flattened, source_map) = flattener.builder.finish();
+
+        let node = &mut graph[*node_index].node;
+        node.source_identity = Some(
+            options
+                .identity
+                .compute(sources[node_index].text.as_bytes()),
+        );
+        node.patched_identity = Some(options.identity.compute(flattened.as_bytes()));
+        node.flattened = flattened;
+        node.source_map = Some(source_map);
+    }
+
+    source_files
+}
+
+/// A file loaded for flattening.
+struct FlattenSource {
+    text: Arc<str>,
+
+    /// Identifies a file containing `#pragma once` or wrapped in an include guard.
+    include_once: bool,
+
+    /// Ranges of the `#pragma once` directives in `text`.
+    pragma_once: Vec<(usize, usize)>,
+}
+
+impl FlattenSource {
+    fn new(text: Arc<str>) -> Self {
+        let directives = scan::scan_directives(&text);
+        let pragma_once = directives
+            .iter()
+            .filter(|directive| directive.name == "pragma" && directive.body.trim() == "once")
+            .map(|directive| (directive.range_start, directive.range_end))
+            .collect::<Vec<(usize, usize)>>();
+        let include_once = !pragma_once.is_empty() || has_include_guard(&text, &directives);
+        FlattenSource {
+            text,
+            include_once,
+            pragma_once,
+        }
+    }
+}
+
+/// Determine whether the whole text is wrapped in the classic include guard idiom:
+///
+/// ```c
+/// #ifndef X // or #if !defined(X)
+/// #define X
+/// ...
+/// #endif
+/// ```
+///
+/// Only comments and `#pragma` directives (e.g. `#pragma warning`) may appear outside the guard.
+fn has_include_guard(text: &str, directives: &[Directive]) -> bool {
+    let guarded = directives
+        .iter()
+        .filter(|directive| directive.name != "pragma")
+        .collect::<Vec<&Directive>>();
+    let (first, last) = match (guarded.first(), guarded.last()) {
+        (Some(first), Some(last)) if guarded.len() >= 3 && last.name == "endif" => (first, last),
+        _ => return false,
+    };
+
+    let guard_macro = match first.name.as_str() {
+        "ifndef" => first.body.trim(),
+        "if" => {
+            let condition = first.body.trim();
+            let condition = condition.strip_prefix('!').map(str::trim_start);
+            match condition.and_then(|condition| condition.strip_prefix("defined")) {
+                Some(operand) => operand
+                    .trim()
+                    .trim_start_matches('(')
+                    .trim_end_matches(')')
+                    .trim(),
+                None => return false,
+            }
+        }
+        _ => return false,
+    };
+    let defines_guard = guarded[1].name == "define"
+        && guarded[1].body.split_whitespace().next() == Some(guard_macro);
+    if guard_macro.is_empty() || !defines_guard {
+        return false;
+    }
+
+    // The opening conditional must be closed by the final directive, without other branches.
+    let mut depth = 0;
+    for (index, directive) in guarded.iter().enumerate() {
+        match directive.name.as_str() {
+            "if" | "ifdef" | "ifndef" => depth += 1,
+            "else" | "elif" | "elifdef" | "elifndef" if depth == 1 => return false,
+            "endif" => {
+                depth -= 1;
+                if depth == 0 && index + 1 != guarded.len() {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    is_blank_except_pragmas(text, 0, first.range_start, directives)
+        && is_blank_except_pragmas(text, last.range_end, text.len(), directives)
+}
+
+/// Determine whether a range of text contains only whitespace, comments and `#pragma` directives.
+fn is_blank_except_pragmas(text: &str, start: usize, end: usize, directives: &[Directive]) -> bool {
+    let mut cursor = start;
+    for directive in directives {
+        if directive.name == "pragma"
+            && directive.range_start >= start
+            && directive.range_end <= end
+        {
+            if !scan::is_blank(&text[cursor..directive.range_start]) {
+                return false;
+            }
+            cursor = directive.range_end;
+        }
+    }
+    scan::is_blank(&text[cursor..end])
+}
+
+/// Shared state while flattening a translation unit.
+struct Flattener<'a> {
+    graph: &'a IncludeNodeGraph,
+    sources: &'a HashMap<NodeIndex, FlattenSource>,
+    options: &'a FlattenOptions,
+    source_files: &'a [PathBuf],
+
+    /// Include-once files already embedded in the translation unit.
+    embedded: HashSet<NodeIndex>,
+
+    builder: SourceMapBuilder,
+}
+
+impl<'a> Flattener<'a> {
+    /// Append the text of a file, recursively embedding its includes.
+    fn expand(&mut self, node_index: NodeIndex) {
+        let graph = self.graph;
+        let source = &self.sources[&node_index];
+        let include_once = self.options.include_once;
+        if include_once && source.include_once {
+            self.embedded.insert(node_index);
+        }
+
         let neighbors = graph
-            .neighbors_directed(*node_index, Direction::Outgoing)
-            .map(|neighbor| {
-                let neighbor_weight = &graph[neighbor];
-                (
-                    neighbor_weight.node.include_file.clone(),
-                    neighbor_weight.node.flattened.to_owned(),
-                )
+            .neighbors_directed(node_index, Direction::Outgoing)
+            .filter(|neighbor| graph[*neighbor].missing.is_none())
+            .map(|neighbor| (graph[neighbor].node.include_file.clone(), neighbor))
+            .collect::<HashMap<PathBuf, NodeIndex>>();
+
+        // Ranges of the original text to replace, either by an embedded file or by nothing.
+        let mut edits = graph[node_index]
+            .includes
+            .iter()
+            .filter_map(|include| {
+                neighbors
+                    .get(&include.include_path)
+                    .map(|neighbor| (include.range_start, include.range_end, Some(*neighbor)))
             })
-            .collect::<Vec<(PathBuf, String)>>();
-
-        if let Some(ref mut node_weight) = graph.node_weight_mut(*node_index) {
-            let mut node = &mut node_weight.node;
-            let mut include_text = node.data_as_string(normalize_endings);
-            node.source_identity = Some(compute_identity(&include_text.as_bytes()));
-            for (ref include_file, ref flattened) in neighbors {
-                if let Some(ref include) = node_weight
-                    .includes
+            .collect::<Vec<(usize, usize, Option<NodeIndex>)>>();
+        if include_once {
+            edits.extend(
+                source
+                    .pragma_once
                     .iter()
-                    .find(|&include| &include.include_path == include_file)
-                {
-                    let patch = format!(
-                        "// EMBED-START - {:?}\n{}\n// EMBED-FINISH - {:?}",
-                        &include_file, &flattened, &include_file
-                    );
-                    include_text.replace_range(include.range_start..include.range_end, &patch);
-                }
+                    .map(|(start, end)| (*start, *end, None)),
+            );
+            edits.sort_by_key(|edit| edit.0);
+        }
+
+        let mut cursor = 0;
+        for (start, end, embed) in edits {
+            self.push_original(node_index, cursor, start);
+            if let Some(include_node) = embed {
+                self.embed(node_index, include_node, end);
             }
-            node.patched_identity = Some(compute_identity(&include_text.as_bytes()));
-            node.flattened = include_text;
+            cursor = end;
+        }
+        self.push_original(node_index, cursor, source.text.len());
+    }
+
+    /// Replace an include directive ending at `directive_end` with the text of the included file.
+    fn embed(&mut self, node_index: NodeIndex, include_node: NodeIndex, directive_end: usize) {
+        if self.options.include_once && self.embedded.contains(&include_node) {
+            return;
         }
-    });
+
+        let graph = self.graph;
+        let include_file = &graph[include_node].node.include_file;
+        self.builder
+            .push_generated(&format!("// EMBED-START - {:?}\n", include_file));
+        if let Some(line) = self
+            .options
+            .line_directive(1, include_file, self.source_files)
+        {
+            self.builder.push_generated(&line);
+            self.builder.push_generated("\n");
+        }
+        self.expand(include_node);
+        self.builder
+            .push_generated(&format!("\n// EMBED-FINISH - {:?}", include_file));
+
+        // The remainder of the directive's (last) line follows, so the next line continues from
+        // the line after the directive.
+        let (directive_line, _) = line_column(&self.sources[&node_index].text, directive_end);
+        if let Some(line) = self.options.line_directive(
+            directive_line + 1,
+            &graph[node_index].node.include_file,
+            self.source_files,
+        ) {
+            self.builder.push_generated("\n");
+            self.builder.push_generated(&line);
+        }
+    }
+
+    /// Append a range of the original text of a file.
+    fn push_original(&mut self, node_index: NodeIndex, start: usize, end: usize) {
+        let text = &self.sources[&node_index].text;
+        let (line, column) = line_column(text, start);
+        self.builder.push_original(
+            &text[start..end],
+            &self.graph[node_index].node.include_file,
+            line,
+            column,
+        );
+    }
+}
+
+/// Get the files reachable from the root node (excluding missing includes), in the order they are
+/// first included.
+fn graph_source_files(graph: &IncludeNodeGraph, root_node: NodeIndex) -> Vec<PathBuf> {
+    fn visit(graph: &IncludeNodeGraph, node_index: NodeIndex, visited: &mut Vec<NodeIndex>) {
+        if visited.contains(&node_index) || graph[node_index].missing.is_some() {
+            return;
+        }
+        visited.push(node_index);
+        for include in &graph[node_index].includes {
+            let include_node = graph
+                .neighbors_directed(node_index, Direction::Outgoing)
+                .find(|neighbor| graph[*neighbor].node.include_file == include.include_path);
+            if let Some(include_node) = include_node {
+                visit(graph, include_node, visited);
+            }
+        }
+    }
+
+    let mut visited = Vec::new();
+    visit(graph, root_node, &mut visited);
+    visited
+        .into_iter()
+        .map(|node_index| graph[node_index].node.include_file.clone())
+        .collect()
 }
 
 /// Check if a given path exists on the file system.
@@ -330,17 +1633,13 @@ pub fn path_exists<P: AsRef<Path>>(path: P) -> bool {
 /// Convert a path into a string
 pub fn path_to_string(path: &Path) -> Option<String> {
     let path_os_str = path.as_os_str();
-    if let Some(path_str) = path_os_str.to_str() {
-        Some(path_str.to_string())
-    } else {
-        None
-    }
+    path_os_str.to_str().map(|path_str| path_str.to_string())
 }
 
 /// Strip the working directory prefix from an include file path
 pub fn path_strip_base(working_dir: &Path, include_file: &Path) -> PathBuf {
     if let Ok(ref prefix) = working_dir.canonicalize() {
-        if let Ok(ref path) = include_file.strip_prefix(&prefix) {
+        if let Ok(path) = include_file.strip_prefix(prefix) {
             path.to_path_buf()
         } else {
             include_file.to_path_buf()
@@ -360,99 +1659,107 @@ pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     Ok(data)
 }
 
+/// Compute the line and column numbers (1-based, column in bytes) of a byte offset into text.
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let prefix = &text[..offset];
+    let line = prefix.matches('\n').count() + 1;
+    let column = match prefix.rfind('\n') {
+        Some(newline) => offset - newline,
+        None => offset + 1,
+    };
+    (line, column)
+}
+
 /// Parse the specified text to extract all relative and absolute include directives.
+///
+/// Directives inside line comments, block comments and string literals are ignored, and
+/// directives split over several lines with backslash continuations are recognized.
 /// See: https://www.wihlidal.com/blog/pipeline/2018-10-04-parsing-shader-includes/
 pub fn parse_includes(input: &str) -> Vec<Include> {
-    // Alternate forms:
-    // r#"(?m)^*\#include\s+["<]([^">]+)*[">]"#
-    // r#"(?m)(^*\#\s*include\s*<([^<>]+)>)|(^\s*\#\s*include\s*"([^"]+)")"#
-
-    lazy_static! {
-        static ref ABSOLUTE_PATH_REGEX: Regex = Regex::new(r#"(?m)^*\#\s*include\s*<([^<>]+)>"#)
-            .expect("failed to compile absolute include path regex");
-    }
-
-    lazy_static! {
-        static ref RELATIVE_PATH_REGEX: Regex = Regex::new(r#"(?m)^*\#\s*include\s*"([^"]+)""#)
-            .expect("failed to compile relative include path regex");
-    }
-
-    let mut references: Vec<Include> = Vec::with_capacity(8);
-
-    // Result will be an iterator over tuples containing the start and end indices for each match in the string
-    let absolute_results = ABSOLUTE_PATH_REGEX.find_iter(input);
-    for absolute_result in absolute_results {
-        let range_start = absolute_result.start();
-        let range_end = absolute_result.end();
-        let range_text = &input[range_start..range_end];
-        let range_caps = ABSOLUTE_PATH_REGEX.captures(range_text).unwrap();
-        let include_path = range_caps.get(1).map_or("", |m| m.as_str());
-        if !include_path.is_empty() {
-            references.push(Include {
-                include_path: Path::new(include_path).to_path_buf(),
-                range_start,
-                range_end,
-                relative_path: false,
-            });
-        }
-    }
-
-    let relative_results = RELATIVE_PATH_REGEX.find_iter(input);
-    for relative_result in relative_results {
-        let range_start = relative_result.start();
-        let range_end = relative_result.end();
-        let range_text = &input[range_start..range_end];
-        let range_text = range_text.trim().trim_matches('\n');
-        let range_caps = RELATIVE_PATH_REGEX.captures(range_text).unwrap();
-        let include_path = range_caps.get(1).map_or("", |m| m.as_str());
-        if !include_path.is_empty() {
-            references.push(Include {
-                include_path: Path::new(include_path).to_path_buf(),
-                range_start,
-                range_end,
-                relative_path: true,
-            });
-        }
-    }
-
-    references
+    scan::scan_directives(input)
+        .iter()
+        .filter_map(|directive| {
+            let operand = directive.include_operand()?;
+            let (line, column) = line_column(input, directive.range_start);
+            Some(Include {
+                include_path: Path::new(operand.path).to_path_buf(),
+                range_start: directive.range_start,
+                range_end: operand.range_end,
+                relative_path: operand.quoted,
+                path_range_start: operand.path_start,
+                path_range_end: operand.path_end,
+                line,
+                column,
+                resolved_dir: None,
+            })
+        })
+        .collect()
 }
 
 /// Extract resolved include directives from the specified text.
 pub fn resolve_includes(text: &str, working_dir: &Path, include_dir: &Path) -> Vec<Include> {
-    let mut includes = parse_includes(&text);
-    for include in &mut includes {
-        let parent_path = if include.relative_path {
-            include_dir
-        } else {
-            working_dir
-        };
+    resolve_includes_in(
+        text,
+        &[include_dir.into()],
+        &[working_dir.into()],
+        &DiskSourceProvider,
+    )
+}
 
-        let full_path = parent_path.join(&include.include_path);
-        if let Ok(ref canonicalized) = &full_path.canonicalize() {
-            include.include_path = canonicalized.to_path_buf();
-        }
+/// Extract resolved include directives from the specified text, using DXC/clang lookup order and
+/// a `SourceProvider` to identify files.
+///
+/// Quoted includes are looked up in `quoted_dirs` (the directory of the including file, followed
+/// by the directories of its includers) and then in `search_dirs`. Angle-bracket includes are only
+/// looked up in `search_dirs`. The first directory containing the file is recorded in
+/// `Include::resolved_dir`; includes that could not be found are kept with their spelled path and
+/// no `resolved_dir`.
+pub fn resolve_includes_in(
+    text: &str,
+    quoted_dirs: &[PathBuf],
+    search_dirs: &[PathBuf],
+    provider: &dyn SourceProvider,
+) -> Vec<Include> {
+    let mut includes = parse_includes(text);
+    for include in &mut includes {
+        resolve_include(include, quoted_dirs, search_dirs, provider);
     }
 
-    includes.retain(|include| {
-        let include_path = Path::new(&include.include_path);
-        let exists = path_exists(&include_path);
-        if !exists {
-            trace!("Include path is invalid: {:?}", include_path);
-        }
-        exists
-    });
-
-    // Sorted references in reverse order to make patching correct, otherwise
-    // applying an earlier patch would invalidate the start and end ranges of
-    // the later patches.
-    includes.sort_by(|a, b| a.range_start.cmp(&b.range_start));
+    // Sorted in order of appearance; patching applies them back to front,
+    // otherwise applying an earlier patch would invalidate the start and end
+    // ranges of the later patches.
+    includes.sort_by_key(|a| a.range_start);
     includes
 }
 
+/// Resolve a parsed include directive, trying the quoted include directories (for quoted
+/// includes) and then the search directories in order.
+pub(crate) fn resolve_include(
+    include: &mut Include,
+    quoted_dirs: &[PathBuf],
+    search_dirs: &[PathBuf],
+    provider: &dyn SourceProvider,
+) {
+    let quoted_dirs = if include.relative_path {
+        quoted_dirs
+    } else {
+        &[]
+    };
+
+    for candidate_dir in quoted_dirs.iter().chain(search_dirs) {
+        let full_path = candidate_dir.join(&include.include_path);
+        if let Some(canonicalized) = provider.canonicalize(&full_path) {
+            include.include_path = canonicalized;
+            include.resolved_dir = Some(candidate_dir.to_path_buf());
+            return;
+        }
+    }
+    trace!("Include path is invalid: {:?}", include.include_path);
+}
+
 /// Print the graph as a tree view to `stdout`.
 pub fn graph_to_stdout(graph: &IncludeNodeGraph, root_node: NodeIndex) -> std::io::Result<()> {
-    print_graph(&graph, root_node)
+    print_graph(graph, root_node)
 }
 
 /// Get a `dot/graphviz` representation of the graph.
@@ -469,11 +1776,43 @@ pub fn graph_to_node_vec(graph: &IncludeNodeGraph) -> Vec<IncludeNode> {
         .collect::<Vec<IncludeNode>>()
 }
 
-/// Get the root node payload from the graph
-pub fn get_root_node(graph: &IncludeNodeGraph) -> Option<IncludeNode> {
-    if let Some(ref node) = graph.raw_nodes().iter().find(|&node| node.weight.is_root) {
-        Some(node.weight.node.clone())
-    } else {
-        None
-    }
+/// Get every include directive in the graph that could not be resolved to a file.
+pub fn graph_missing_includes(graph: &IncludeNodeGraph) -> Vec<MissingInclude> {
+    graph
+        .raw_nodes()
+        .iter()
+        .filter_map(|node| node.weight.missing.clone())
+        .collect::<Vec<MissingInclude>>()
+}
+
+/// Get every include directive on an inactive conditional branch, along with its includer.
+pub fn graph_inactive_includes(graph: &IncludeNodeGraph) -> Vec<(PathBuf, Include)> {
+    graph
+        .raw_nodes()
+        .iter()
+        .flat_map(|node| {
+            node.weight
+                .inactive_includes
+                .iter()
+                .map(move |include| (node.weight.node.include_file.clone(), include.clone()))
+        })
+        .collect::<Vec<(PathBuf, Include)>>()
+}
+
+/// Get every root node of the graph (the files passed to `traverse_build_with` and friends).
+pub fn get_root_nodes(graph: &IncludeNodeGraph) -> Vec<NodeIndex> {
+    graph
+        .node_indices()
+        .filter(|node_index| graph[*node_index].is_root)
+        .collect::<Vec<NodeIndex>>()
 }
+
+/// Get the root node payload from the graph; the first root if the graph has several roots
+/// (see `get_root_nodes`).
+pub fn get_root_node(graph: &IncludeNodeGraph) -> Option<IncludeNode> {
+    graph
+        .raw_nodes()
+        .iter()
+        .find(|&node| node.weight.is_root)
+        .map(|node| node.weight.node.clone())
+}
@@ -0,0 +1,150 @@
+//! Parallel graph construction and patching, enabled with the `parallel` feature.
+//!
+//! Both passes produce graphs and identities identical to the serial `traverse_build_with` and
+//! `traverse_patch_with`: files are read, decoded, parsed and their includes resolved
+//! concurrently ahead of time, and the graph is then assembled in the same order as the serial
+//! walk.
+
+use crate::{
+    build_graph, include_identities, parse_includes, patch_node, resolve_include, salt_root,
+    BuildOptions, IncludeError, IncludeNode, IncludeNodeGraph, IncludeNodeLevel, PatchOptions,
+    PrefetchedFile, SourceProvider,
+};
+use petgraph::prelude::*;
+use petgraph::visit::Walker;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Traverse the graph in order to construct the structure and meta data, reading, decoding and
+/// parsing files concurrently.
+pub fn traverse_build_parallel(
+    graph: &mut IncludeNodeGraph,
+    working_dir: &Path,
+    include_file: &Path,
+    level: IncludeNodeLevel,
+    options: &BuildOptions,
+    provider: &dyn SourceProvider,
+) -> Result<NodeIndex, IncludeError> {
+    let include_file = provider
+        .canonicalize(include_file)
+        .unwrap_or_else(|| include_file.to_path_buf());
+    let mut prefetched = prefetch(working_dir, &include_file, options, provider);
+    build_graph(
+        graph,
+        working_dir,
+        &include_file,
+        level,
+        options,
+        provider,
+        &mut prefetched,
+    )
+}
+
+/// Load the files reachable from the root one wave of includes at a time, each wave in parallel.
+///
+/// Quoted includes are only looked up next to their includer here; the ones that aren't found
+/// there are resolved again when the graph is assembled (the directories of the includer's own
+/// includers come first), and any file this misses is loaded then.
+fn prefetch(
+    working_dir: &Path,
+    include_file: &Path,
+    options: &BuildOptions,
+    provider: &dyn SourceProvider,
+) -> HashMap<PathBuf, PrefetchedFile> {
+    let search_dirs = std::iter::once(working_dir.to_path_buf())
+        .chain(options.include_dirs.iter().cloned())
+        .collect::<Vec<PathBuf>>();
+
+    let mut prefetched = HashMap::new();
+    let mut queued = HashSet::new();
+    let mut wave = vec![include_file.to_path_buf()];
+    queued.insert(include_file.to_path_buf());
+    while !wave.is_empty() {
+        let loaded = wave
+            .par_iter()
+            .map(|include_file| {
+                let include_text = IncludeNode::new(working_dir, include_file)
+                    .data_as_string_from(provider, options.normalize_endings);
+                let quoted_dirs = include_file
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .into_iter()
+                    .collect::<Vec<PathBuf>>();
+                let mut parsed = parse_includes(&include_text);
+                parsed.sort_by_key(|include| include.range_start);
+                let includes = parsed
+                    .into_iter()
+                    .map(|parsed| {
+                        let mut resolved = parsed.clone();
+                        resolve_include(&mut resolved, &quoted_dirs, &search_dirs, provider);
+                        (parsed, resolved)
+                    })
+                    .collect::<Vec<_>>();
+                (include_file.clone(), include_text, includes)
+            })
+            .collect::<Vec<_>>();
+
+        wave = Vec::new();
+        for (include_file, include_text, includes) in loaded {
+            for (_, include) in &includes {
+                if include.resolved_dir.is_some() && queued.insert(include.include_path.clone()) {
+                    wave.push(include.include_path.clone());
+                }
+            }
+            prefetched.insert(
+                include_file,
+                PrefetchedFile {
+                    text: include_text,
+                    includes: Some(includes),
+                },
+            );
+        }
+    }
+    prefetched
+}
+
+/// Traverse the graph in order to patch in Merkle identities for all include directives,
+/// patching every node whose includes are already patched in parallel.
+pub fn traverse_patch_parallel(
+    graph: &mut IncludeNodeGraph,
+    root_node: NodeIndex,
+    options: &PatchOptions,
+    provider: &dyn SourceProvider,
+) {
+    // Group nodes by height (the longest path to a leaf), so each group only includes nodes of
+    // lower groups. Post-order visits every node after the nodes it includes.
+    let mut heights: HashMap<NodeIndex, usize> = HashMap::new();
+    let mut levels: Vec<Vec<NodeIndex>> = Vec::new();
+    for node_index in DfsPostOrder::new(&*graph, root_node).iter(&*graph) {
+        let height = graph
+            .neighbors_directed(node_index, Direction::Outgoing)
+            .map(|neighbor| heights[&neighbor] + 1)
+            .max()
+            .unwrap_or_default();
+        heights.insert(node_index, height);
+        if graph[node_index].missing.is_some() {
+            continue;
+        }
+        if levels.len() <= height {
+            levels.resize(height + 1, Vec::new());
+        }
+        levels[height].push(node_index);
+    }
+
+    for level in levels {
+        let patched = level
+            .par_iter()
+            .map(|node_index| {
+                let identities = include_identities(graph, *node_index);
+                let patched = patch_node(&graph[*node_index], &identities, options, provider);
+                (*node_index, patched)
+            })
+            .collect::<Vec<_>>();
+        for (node_index, patched) in patched {
+            patched.store(&mut graph[node_index].node);
+        }
+    }
+
+    salt_root(graph, root_node, options);
+}
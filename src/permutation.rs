@@ -0,0 +1,198 @@
+//! Permutation-specific include graphs and identities.
+//!
+//! A shader compiled under different macro definitions can pull in different headers, so each
+//! permutation gets its own include graph (built with conditional-compilation aware discovery)
+//! and an identity that also covers its defines.
+
+use crate::provider::CachingSourceProvider;
+use crate::{
+    compute_domain_identity, patch_graph, traverse_build_with, BuildOptions, IdentityFormat,
+    IncludeError, IncludeNodeGraph, PatchCache, PatchOptions, SourceProvider,
+};
+use petgraph::graph::NodeIndex;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A macro that varies between permutations, along with each of its values.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct PermutationAxis {
+    /// Name of the macro
+    pub name: String,
+
+    /// Values of the macro; `None` leaves the macro undefined
+    pub values: Vec<Option<String>>,
+}
+
+/// The permutations of a shader, as every combination of the values of its axes.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct PermutationMatrix {
+    /// Macros that vary between permutations
+    pub axes: Vec<PermutationAxis>,
+}
+
+impl PermutationMatrix {
+    /// Create an empty `PermutationMatrix`, which has a single permutation.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a macro that varies between permutations.
+    pub fn add_axis<N: Into<String>>(&mut self, name: N, values: Vec<Option<String>>) {
+        self.axes.push(PermutationAxis {
+            name: name.into(),
+            values,
+        });
+    }
+
+    /// Enumerate the defines of every permutation, applying each combination of axis values over
+    /// the base defines; the last axis varies fastest.
+    pub fn define_sets(&self, base: &BTreeMap<String, String>) -> Vec<BTreeMap<String, String>> {
+        let mut define_sets = vec![base.clone()];
+        for axis in &self.axes {
+            define_sets = define_sets
+                .iter()
+                .flat_map(|defines| {
+                    axis.values.iter().map(move |value| {
+                        let mut defines = defines.clone();
+                        match value {
+                            Some(value) => defines.insert(axis.name.clone(), value.clone()),
+                            None => defines.remove(&axis.name),
+                        };
+                        defines
+                    })
+                })
+                .collect();
+        }
+        define_sets
+    }
+}
+
+/// The include graph and identity of one permutation of a shader.
+#[derive(Debug, Clone)]
+pub struct Permutation {
+    /// Macros defined for the permutation (including `BuildOptions::defines`)
+    pub defines: BTreeMap<String, String>,
+
+    /// Include graph of the permutation, patched with Merkle identities
+    pub graph: IncludeNodeGraph,
+
+    /// Root node of the graph
+    pub root_node: NodeIndex,
+
+    /// Identity of the permutation, combining the patched (or salted, when
+    /// `PatchOptions::context` is set) identity of the root with the defines
+    pub identity: String,
+}
+
+/// Compute the identity of a permutation from the patched identity of its root and its defines.
+///
+/// The data hashed is domain separated and length prefixed, so it can't collide with the
+/// identity of file contents or of a different define set.
+pub fn permutation_identity(
+    patched_identity: &str,
+    defines: &BTreeMap<String, String>,
+    format: &IdentityFormat,
+) -> String {
+    let defines = defines
+        .iter()
+        .flat_map(|(name, value)| [name.as_str(), value.as_str()]);
+    compute_domain_identity(
+        format,
+        "permutation",
+        std::iter::once(patched_identity).chain(defines),
+    )
+}
+
+/// Build and patch the include graph of a shader for one set of defines, which are applied over
+/// `BuildOptions::defines`.
+pub fn build_permutation(
+    working_dir: &Path,
+    include_file: &Path,
+    defines: &BTreeMap<String, String>,
+    build_options: &BuildOptions,
+    patch_options: &PatchOptions,
+    provider: &dyn SourceProvider,
+) -> Result<Permutation, IncludeError> {
+    let mut permutation_defines = build_options.defines.clone().unwrap_or_default();
+    permutation_defines.extend(defines.clone());
+    build(
+        working_dir,
+        include_file,
+        permutation_defines,
+        build_options,
+        patch_options,
+        provider,
+        None,
+    )
+}
+
+/// Build and patch the include graph of every permutation in a matrix.
+///
+/// Files are read once for the whole batch, and files whose includes resolve to the same
+/// identities in several permutations are only patched once.
+pub fn build_permutations(
+    working_dir: &Path,
+    include_file: &Path,
+    matrix: &PermutationMatrix,
+    build_options: &BuildOptions,
+    patch_options: &PatchOptions,
+    provider: &dyn SourceProvider,
+) -> Result<Vec<Permutation>, IncludeError> {
+    let provider = CachingSourceProvider::new(provider);
+    let mut cache = PatchCache::new();
+    let base = build_options.defines.clone().unwrap_or_default();
+    matrix
+        .define_sets(&base)
+        .into_iter()
+        .map(|defines| {
+            build(
+                working_dir,
+                include_file,
+                defines,
+                build_options,
+                patch_options,
+                &provider,
+                Some(&mut cache),
+            )
+        })
+        .collect()
+}
+
+fn build(
+    working_dir: &Path,
+    include_file: &Path,
+    defines: BTreeMap<String, String>,
+    build_options: &BuildOptions,
+    patch_options: &PatchOptions,
+    provider: &dyn SourceProvider,
+    cache: Option<&mut PatchCache>,
+) -> Result<Permutation, IncludeError> {
+    let build_options = BuildOptions {
+        defines: Some(defines.clone()),
+        ..build_options.clone()
+    };
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        working_dir,
+        include_file,
+        0,
+        &build_options,
+        provider,
+    )?;
+    patch_graph(&mut graph, &[root_node], patch_options, provider, cache);
+
+    let root = &graph[root_node].node;
+    let root_identity = root
+        .salted_identity
+        .as_ref()
+        .or(root.patched_identity.as_ref())
+        .cloned()
+        .unwrap_or_default();
+    Ok(Permutation {
+        identity: permutation_identity(&root_identity, &defines, &patch_options.identity),
+        defines,
+        graph,
+        root_node,
+    })
+}
@@ -0,0 +1,164 @@
+use crate::read_file;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Provides the files that include graphs are built from.
+///
+/// Implementations decide how paths are identified (canonicalized) and how file contents are
+/// read, which allows the Merkle pipeline to run over sources that aren't on disk.
+pub trait SourceProvider: Send + Sync {
+    /// Get the canonical path identifying a file, or `None` if the file does not exist.
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf>;
+
+    /// Read the contents of a file identified by a canonical path.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Get the modification time and size of a file identified by a canonical path, used to
+    /// cheaply detect changes; `None` (the default) means changes are detected by comparing
+    /// contents.
+    fn stamp(&self, _path: &Path) -> Option<FileStamp> {
+        None
+    }
+}
+
+/// Modification time and size of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileStamp {
+    /// Last modification time, if the platform records it
+    pub modified: Option<SystemTime>,
+
+    /// Size in bytes
+    pub len: u64,
+}
+
+/// Provides files from the local file system.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskSourceProvider;
+
+impl SourceProvider for DiskSourceProvider {
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+        path.canonicalize().ok()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        read_file(path)
+    }
+
+    fn stamp(&self, path: &Path) -> Option<FileStamp> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        })
+    }
+}
+
+/// Provides files held in memory, such as unsaved editor buffers.
+///
+/// Paths are canonicalized lexically (removing `.` and resolving `..` components), so files
+/// should be inserted using the same absolute or relative form used for lookups.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MemorySourceProvider {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemorySourceProvider {
+    /// Create an empty `MemorySourceProvider`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add or replace the contents of a file.
+    pub fn insert<P: AsRef<Path>, D: Into<Vec<u8>>>(&mut self, path: P, data: D) {
+        self.files
+            .insert(normalize_path(path.as_ref()), data.into());
+    }
+
+    /// Remove a file, returning its contents if it existed.
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> Option<Vec<u8>> {
+        self.files.remove(&normalize_path(path.as_ref()))
+    }
+}
+
+impl SourceProvider for MemorySourceProvider {
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+        let path = normalize_path(path);
+        if self.files.contains_key(&path) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files
+            .get(&normalize_path(path))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?}", path)))
+    }
+}
+
+/// Caches the canonical paths and file contents of another `SourceProvider`, so that repeated
+/// builds over the same files (such as permutations of a shader) only touch them once.
+pub(crate) struct CachingSourceProvider<'a> {
+    provider: &'a dyn SourceProvider,
+    canonical: Mutex<HashMap<PathBuf, Option<PathBuf>>>,
+    files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl<'a> CachingSourceProvider<'a> {
+    pub fn new(provider: &'a dyn SourceProvider) -> Self {
+        CachingSourceProvider {
+            provider,
+            canonical: Mutex::new(HashMap::new()),
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<'a> SourceProvider for CachingSourceProvider<'a> {
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+        let mut canonical = self.canonical.lock().unwrap();
+        canonical
+            .entry(path.to_path_buf())
+            .or_insert_with(|| self.provider.canonicalize(path))
+            .clone()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.files.lock().unwrap().get(path) {
+            return Ok(data.clone());
+        }
+        let data = self.provider.read(path)?;
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), data.clone());
+        Ok(data)
+    }
+
+    fn stamp(&self, path: &Path) -> Option<FileStamp> {
+        self.provider.stamp(path)
+    }
+}
+
+/// Lexically normalize a path by removing `.` components and resolving `..` components.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push(component);
+                }
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
@@ -0,0 +1,119 @@
+//! Rebuilding include trees from a content store.
+//!
+//! A patched file references each of its includes by identity, so a patched root identity and a
+//! `ContentStore` holding the output of `ContentStore::publish_graph` are enough to fetch every
+//! file the root depends on, then flatten the tree or write it out to be compiled.
+
+use crate::{
+    parse_includes, traverse_build_with, traverse_flatten_with, BuildOptions, ContentStore,
+    FlattenOptions, IncludeNodeGraph, MemorySourceProvider, PatchOptions, SourceProvider,
+};
+use petgraph::graph::NodeIndex;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The include tree of a patched root, rebuilt from a `ContentStore`.
+///
+/// Files are named by their include path (`PatchOptions::identity_path`) under a virtual working
+/// directory.
+#[derive(Debug, Clone)]
+pub struct Reconstruction {
+    /// Include graph of the root
+    pub graph: IncludeNodeGraph,
+
+    /// Root node of the graph
+    pub root_node: NodeIndex,
+
+    /// Provides the fetched files
+    pub provider: MemorySourceProvider,
+
+    /// Include path of every fetched file, starting with the root
+    pub files: Vec<PathBuf>,
+
+    /// Virtual working directory the files are in
+    pub working_dir: PathBuf,
+}
+
+impl Reconstruction {
+    /// Flatten the tree, returning the flattened text of the root.
+    pub fn flatten(&mut self, options: &FlattenOptions) -> String {
+        traverse_flatten_with(&mut self.graph, self.root_node, options, &self.provider);
+        self.graph[self.root_node].node.flattened.clone()
+    }
+
+    /// Write every file to a directory, keeping the include paths, so that the root can be
+    /// compiled with the directory as an include search path. Returns the path of the root.
+    pub fn materialize(&self, output_dir: &Path) -> io::Result<PathBuf> {
+        for file in &self.files {
+            let data = self.provider.read(&self.working_dir.join(file))?;
+            let path = output_dir.join(file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, data)?;
+        }
+        Ok(output_dir.join(&self.files[0]))
+    }
+}
+
+/// Rebuild the include tree of a patched root identity, fetching every file it (transitively)
+/// includes from a store.
+///
+/// `options` must have the identity prefix and suffix the tree was patched with, to recognise
+/// identity-form include directives; other directives (such as includes that were unresolved
+/// when patching) are kept as missing nodes. Fails with `io::ErrorKind::NotFound` if a file isn't
+/// in the store, and `io::ErrorKind::InvalidData` if a stored file is corrupt.
+pub fn reconstruct(
+    store: &ContentStore,
+    root_identity: &str,
+    options: &PatchOptions,
+) -> io::Result<Reconstruction> {
+    let working_dir = PathBuf::from("/");
+    let mut provider = MemorySourceProvider::new();
+    let mut files = Vec::new();
+    let mut fetched = HashSet::new();
+    let mut pending = vec![(root_identity.to_string(), None)];
+    fetched.insert(root_identity.to_string());
+    while let Some((identity, includer)) = pending.pop() {
+        let data = store.get(&identity).map_err(|error| {
+            let includer = includer
+                .map(|includer: String| format!(" (included by {})", includer))
+                .unwrap_or_default();
+            io::Error::new(error.kind(), format!("{}{}: {}", identity, includer, error))
+        })?;
+
+        let text = String::from_utf8_lossy(&data);
+        for include in parse_includes(&text) {
+            let include_path = include.include_path.to_string_lossy();
+            if let Some(include_identity) = options.identity_from_path(&include_path) {
+                if fetched.insert(include_identity.to_string()) {
+                    pending.push((include_identity.to_string(), Some(identity.clone())));
+                }
+            }
+        }
+
+        let file = PathBuf::from(options.identity_path(&identity));
+        provider.insert(working_dir.join(&file), data);
+        files.push(file);
+    }
+
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        &working_dir,
+        &working_dir.join(&files[0]),
+        0,
+        &BuildOptions::default(),
+        &provider,
+    )
+    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    Ok(Reconstruction {
+        graph,
+        root_node,
+        provider,
+        files,
+        working_dir,
+    })
+}
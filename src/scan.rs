@@ -0,0 +1,399 @@
+//! Lexical scanning of preprocessor directives.
+//!
+//! The scanner walks the text once, skipping line comments, block comments and string or
+//! character literals, and honours backslash line continuations. A directive is a `#` that is the
+//! first token on its logical line, matching the rules of the C preprocessor.
+
+/// A preprocessor directive spanning one logical line.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Directive {
+    /// Directive name (`include`, `define`, `if`, ...); empty for a null directive.
+    pub name: String,
+
+    /// Text following the name, with line continuations spliced and comments replaced by a space.
+    pub body: String,
+
+    /// Byte offset in the original text for each byte of `body`.
+    body_offsets: Vec<usize>,
+
+    /// Start position in text buffer of the directive (the `#` character).
+    pub range_start: usize,
+
+    /// End position in text buffer of the directive's logical line (excluding the line feed).
+    pub range_end: usize,
+}
+
+/// The path operand of an include directive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IncludeOperand<'a> {
+    /// Path as spelled between the delimiters.
+    pub path: &'a str,
+
+    /// Identifies a quoted (`"path"`) rather than angle-bracket (`<path>`) include.
+    pub quoted: bool,
+
+    /// Position in the original text of the first byte of the path.
+    pub path_start: usize,
+
+    /// Position in the original text just past the last byte of the path.
+    pub path_end: usize,
+
+    /// Position in the original text just past the closing delimiter.
+    pub range_end: usize,
+}
+
+impl Directive {
+    /// Map a byte position within `body` back to a position in the original text.
+    pub fn body_offset(&self, index: usize) -> usize {
+        self.body_offsets
+            .get(index)
+            .cloned()
+            .unwrap_or(self.range_end)
+    }
+
+    /// Extract the path operand of an `#include` directive.
+    pub fn include_operand(&self) -> Option<IncludeOperand<'_>> {
+        if self.name != "include" {
+            return None;
+        }
+
+        let body = self.body.as_bytes();
+        let open = body.iter().position(|c| !is_whitespace(*c))?;
+        let (close_char, quoted) = match body[open] {
+            b'"' => (b'"', true),
+            b'<' => (b'>', false),
+            _ => return None,
+        };
+        let close = open + 1 + body[open + 1..].iter().position(|c| *c == close_char)?;
+        if close == open + 1 {
+            return None;
+        }
+
+        Some(IncludeOperand {
+            path: &self.body[open + 1..close],
+            quoted,
+            path_start: self.body_offset(open + 1),
+            path_end: self.body_offset(close - 1) + 1,
+            range_end: self.body_offset(close) + 1,
+        })
+    }
+}
+
+/// Scan the specified text and return every preprocessor directive in order of appearance.
+pub(crate) fn scan_directives(text: &str) -> Vec<Directive> {
+    let bytes = text.as_bytes();
+    let mut directives = Vec::new();
+    let mut index = 0;
+
+    // Only whitespace and comments have been seen since the start of the logical line.
+    let mut line_start = true;
+
+    while index < bytes.len() {
+        if let Some(next) = skip_continuation(bytes, index) {
+            index = next;
+            continue;
+        }
+
+        match bytes[index] {
+            b'\n' => {
+                line_start = true;
+                index += 1;
+            }
+            b'/' if peek(bytes, index + 1) == Some(b'/') => {
+                index = skip_line_comment(bytes, index);
+            }
+            b'/' if peek(bytes, index + 1) == Some(b'*') => {
+                // Comments are replaced by a single space, so they don't end the line start.
+                index = skip_block_comment(bytes, index);
+            }
+            b'"' | b'\'' => {
+                line_start = false;
+                index = skip_literal(bytes, index);
+            }
+            b'#' if line_start => {
+                let directive = read_directive(bytes, index);
+                index = directive.range_end;
+                line_start = false;
+                directives.push(directive);
+            }
+            c if is_whitespace(c) => {
+                index += 1;
+            }
+            _ => {
+                line_start = false;
+                index += 1;
+            }
+        }
+    }
+
+    directives
+}
+
+/// Determine whether the specified text contains only whitespace and comments.
+pub(crate) fn is_blank(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        if let Some(next) = skip_continuation(bytes, index) {
+            index = next;
+            continue;
+        }
+
+        match bytes[index] {
+            b'/' if peek(bytes, index + 1) == Some(b'/') => {
+                index = skip_line_comment(bytes, index);
+            }
+            b'/' if peek(bytes, index + 1) == Some(b'*') => {
+                index = skip_block_comment(bytes, index);
+            }
+            c if c == b'\n' || is_whitespace(c) => {
+                index += 1;
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Collect the logical line of a directive starting at the `#` character.
+fn read_directive(bytes: &[u8], start: usize) -> Directive {
+    let mut logical: Vec<u8> = Vec::new();
+    let mut offsets: Vec<usize> = Vec::new();
+    let mut index = start + 1;
+
+    while index < bytes.len() && bytes[index] != b'\n' {
+        if let Some(next) = skip_continuation(bytes, index) {
+            index = next;
+            continue;
+        }
+
+        match bytes[index] {
+            b'/' if peek(bytes, index + 1) == Some(b'/') => {
+                index = skip_line_comment(bytes, index);
+            }
+            b'/' if peek(bytes, index + 1) == Some(b'*') => {
+                logical.push(b' ');
+                offsets.push(index);
+                index = skip_block_comment(bytes, index);
+            }
+            b'"' | b'\'' => {
+                let end = skip_literal(bytes, index);
+                logical.extend_from_slice(&bytes[index..end]);
+                offsets.extend(index..end);
+                index = end;
+            }
+            b'<' if is_include_name(&logical) => {
+                // Header names are not tokenized, so `<a//b.h>` doesn't start a comment.
+                let end = bytes[index..]
+                    .iter()
+                    .position(|c| *c == b'>' || *c == b'\n')
+                    .map_or(bytes.len(), |end| {
+                        if bytes[index + end] == b'>' {
+                            index + end + 1
+                        } else {
+                            index + end
+                        }
+                    });
+                logical.extend_from_slice(&bytes[index..end]);
+                offsets.extend(index..end);
+                index = end;
+            }
+            c => {
+                logical.push(c);
+                offsets.push(index);
+                index += 1;
+            }
+        }
+    }
+
+    let name_start = logical
+        .iter()
+        .position(|c| !is_whitespace(*c))
+        .unwrap_or(logical.len());
+    let name_end = name_start
+        + logical[name_start..]
+            .iter()
+            .position(|c| !is_identifier(*c))
+            .unwrap_or(logical.len() - name_start);
+
+    Directive {
+        name: String::from_utf8_lossy(&logical[name_start..name_end]).into_owned(),
+        body: String::from_utf8_lossy(&logical[name_end..]).into_owned(),
+        body_offsets: offsets.split_off(name_end),
+        range_start: start,
+        range_end: index,
+    }
+}
+
+fn is_include_name(logical: &[u8]) -> bool {
+    std::str::from_utf8(logical).map(str::trim) == Ok("include")
+}
+
+fn is_whitespace(c: u8) -> bool {
+    c == b' ' || c == b'\t' || c == b'\r' || c == 0x0b || c == 0x0c
+}
+
+fn is_identifier(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+fn peek(bytes: &[u8], index: usize) -> Option<u8> {
+    bytes.get(index).cloned()
+}
+
+/// If a backslash line continuation starts at `index`, return the position following it.
+fn skip_continuation(bytes: &[u8], index: usize) -> Option<usize> {
+    if bytes[index] != b'\\' {
+        return None;
+    }
+    match peek(bytes, index + 1) {
+        Some(b'\n') => Some(index + 2),
+        Some(b'\r') if peek(bytes, index + 2) == Some(b'\n') => Some(index + 3),
+        _ => None,
+    }
+}
+
+/// Skip a `//` comment, returning the position of the terminating line feed (or end of text).
+fn skip_line_comment(bytes: &[u8], start: usize) -> usize {
+    let mut index = start + 2;
+    while index < bytes.len() && bytes[index] != b'\n' {
+        index = skip_continuation(bytes, index).unwrap_or(index + 1);
+    }
+    index
+}
+
+/// Skip a `/* */` comment, returning the position following it (or end of text).
+fn skip_block_comment(bytes: &[u8], start: usize) -> usize {
+    let mut index = start + 2;
+    while index < bytes.len() {
+        if bytes[index] == b'*' && peek(bytes, index + 1) == Some(b'/') {
+            return index + 2;
+        }
+        index += 1;
+    }
+    index
+}
+
+/// Skip a string or character literal, returning the position following the closing quote. An
+/// unterminated literal ends at the line feed.
+fn skip_literal(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut index = start + 1;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 2,
+            b'\n' => return index,
+            c if c == quote => return index + 1,
+            _ => index += 1,
+        }
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(text: &str) -> Vec<String> {
+        scan_directives(text)
+            .into_iter()
+            .map(|directive| directive.name)
+            .collect()
+    }
+
+    #[test]
+    fn skips_line_comments() {
+        let text = "// #include \"a.h\"\n#include \"b.h\" // #include \"c.h\"\n";
+        let directives = scan_directives(text);
+        assert_eq!(directives.len(), 1);
+        assert_eq!(directives[0].include_operand().unwrap().path, "b.h");
+    }
+
+    #[test]
+    fn skips_multi_line_block_comments() {
+        let text = "/*\n#include \"a.h\"\n*/\n#define A /* one\ntwo */ 1\n#include \"b.h\"\n";
+        assert_eq!(names(text), ["define", "include"]);
+        let directives = scan_directives(text);
+        assert_eq!(directives[0].body, " A   1");
+        assert_eq!(directives[1].include_operand().unwrap().path, "b.h");
+    }
+
+    #[test]
+    fn skips_string_literals() {
+        // A `/*` in a literal doesn't start a comment that hides the next directive.
+        let text = "const char* a = \"\\\"/*\";\nchar b = '\\'';\n#include <b.h>\n// */\n";
+        let directives = scan_directives(text);
+        assert_eq!(directives.len(), 1);
+        assert_eq!(directives[0].include_operand().unwrap().path, "b.h");
+
+        // A directive doesn't start after a literal on the same line.
+        assert_eq!(names("\"x\" #include \"a.h\"\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn splices_backslash_continuations() {
+        let text = "#inc\\\nlude \\\r\n\"a.h\"\n// comment \\\n#include \"b.h\"\n";
+        let directives = scan_directives(text);
+        assert_eq!(directives.len(), 1);
+        let directive = &directives[0];
+        assert_eq!(directive.name, "include");
+        assert_eq!(directive.range_end, text.find("\n// ").unwrap());
+        let operand = directive.include_operand().unwrap();
+        assert_eq!(operand.path, "a.h");
+        assert_eq!(&text[operand.path_start..operand.path_end], "a.h");
+    }
+
+    #[test]
+    fn directive_after_block_comment() {
+        let text = "int a;\n/*c*/ #include \"a.h\"\n";
+        let directives = scan_directives(text);
+        assert_eq!(directives.len(), 1);
+        assert_eq!(directives[0].range_start, text.find('#').unwrap());
+        assert_eq!(directives[0].include_operand().unwrap().path, "a.h");
+    }
+
+    #[test]
+    fn include_offsets() {
+        let text = "float a;\n  #  include  \"dir/a.h\" // trailing\n#include <b//c.h>\n";
+        let directives = scan_directives(text);
+        assert_eq!(directives.len(), 2);
+
+        let quoted = &directives[0];
+        assert_eq!(quoted.range_start, 11);
+        assert_eq!(quoted.range_end, 44);
+        let operand = quoted.include_operand().unwrap();
+        assert!(operand.quoted);
+        assert_eq!((operand.path_start, operand.path_end), (24, 31));
+        assert_eq!(operand.range_end, 32);
+        assert_eq!(&text[operand.path_start..operand.path_end], "dir/a.h");
+
+        let angle = &directives[1];
+        assert_eq!(angle.range_start, 45);
+        assert_eq!(angle.range_end, 62);
+        let operand = angle.include_operand().unwrap();
+        assert!(!operand.quoted);
+        assert_eq!(operand.path, "b//c.h");
+        assert_eq!((operand.path_start, operand.path_end), (55, 61));
+        assert_eq!(operand.range_end, 62);
+    }
+
+    #[test]
+    fn rejects_malformed_includes() {
+        for text in [
+            "#include\n",
+            "#include \"\"\n",
+            "#include \"a.h\n",
+            "#include A\n",
+        ] {
+            let directives = scan_directives(text);
+            assert_eq!(directives.len(), 1);
+            assert_eq!(directives[0].include_operand(), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn blank_text() {
+        assert!(is_blank("  // comment\n/* block\n */ \\\n\t\n"));
+        assert!(!is_blank("/* block */ x"));
+    }
+}
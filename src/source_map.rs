@@ -0,0 +1,176 @@
+use std::path::{Path, PathBuf};
+
+/// Maps positions in flattened text back to the original source files.
+///
+/// Serializable (e.g. to JSON) when the `serde` feature is enabled.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceMap {
+    /// Original source files, referenced by index from the segments
+    pub files: Vec<PathBuf>,
+
+    /// Mapped segments ordered by output position; a segment never spans a line feed and covers
+    /// the text up to the next segment, and the last segment is an empty generated segment that
+    /// marks the end of the text
+    pub segments: Vec<SourceMapSegment>,
+}
+
+/// A run of flattened text that maps to a contiguous run of an original source file.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceMapSegment {
+    /// Start position in the flattened text
+    pub output_offset: usize,
+
+    /// Line number (1-based) in the flattened text
+    pub output_line: usize,
+
+    /// Column number (1-based, in bytes) in the flattened text
+    pub output_column: usize,
+
+    /// Index of the original file in `SourceMap::files`, or `None` for generated text such as
+    /// embed comments and `#line` directives
+    pub file: Option<usize>,
+
+    /// Line number (1-based) in the original file
+    pub original_line: usize,
+
+    /// Column number (1-based, in bytes) in the original file
+    pub original_column: usize,
+}
+
+/// A position in an original source file.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// Path of the original include file
+    pub include_file: PathBuf,
+
+    /// Line number (1-based)
+    pub line: usize,
+
+    /// Column number (1-based, in bytes)
+    pub column: usize,
+}
+
+impl SourceMap {
+    /// Map a byte offset in the flattened text to its original location.
+    ///
+    /// Returns `None` for generated text, or positions past the end of the map.
+    pub fn lookup_offset(&self, offset: usize) -> Option<SourceLocation> {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.output_offset <= offset);
+        let segment = self.segments.get(index.checked_sub(1)?)?;
+        self.location(segment, offset - segment.output_offset)
+    }
+
+    /// Map a line and column (both 1-based) in the flattened text to its original location.
+    ///
+    /// Returns `None` for generated text, or positions past the end of the map.
+    pub fn lookup_line_column(&self, line: usize, column: usize) -> Option<SourceLocation> {
+        let index = self.segments.partition_point(|segment| {
+            (segment.output_line, segment.output_column) <= (line, column)
+        });
+        let segment = self.segments.get(index.checked_sub(1)?)?;
+        let next = self.segments.get(index)?;
+        if segment.output_line != line {
+            return None;
+        }
+        let delta = column - segment.output_column;
+        if delta >= next.output_offset - segment.output_offset {
+            return None;
+        }
+        self.location(segment, delta)
+    }
+
+    /// Map a line (1-based) in the flattened text to its original location.
+    pub fn lookup_line(&self, line: usize) -> Option<SourceLocation> {
+        self.lookup_line_column(line, 1)
+    }
+
+    fn location(&self, segment: &SourceMapSegment, delta: usize) -> Option<SourceLocation> {
+        let file = segment.file?;
+        Some(SourceLocation {
+            include_file: self.files[file].clone(),
+            line: segment.original_line,
+            column: segment.original_column + delta,
+        })
+    }
+
+    fn file_index(&mut self, include_file: &Path) -> usize {
+        match self.files.iter().position(|file| file == include_file) {
+            Some(index) => index,
+            None => {
+                self.files.push(include_file.to_path_buf());
+                self.files.len() - 1
+            }
+        }
+    }
+}
+
+/// Assembles flattened text front-to-back while recording its source map.
+#[derive(Default, Debug)]
+pub(crate) struct SourceMapBuilder {
+    text: String,
+    map: SourceMap,
+    line: usize,
+    column: usize,
+}
+
+impl SourceMapBuilder {
+    pub fn new() -> Self {
+        SourceMapBuilder {
+            line: 1,
+            column: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Append a run of an original file, starting at the given original line and column.
+    pub fn push_original(&mut self, text: &str, include_file: &Path, line: usize, column: usize) {
+        let file = self.map.file_index(include_file);
+        let (mut line, mut column) = (line, column);
+        for piece in text.split_inclusive('\n') {
+            self.push_segment(Some(file), line, column);
+            self.push_text(piece);
+            line += 1;
+            column = 1;
+        }
+    }
+
+    /// Append generated text that has no original location.
+    pub fn push_generated(&mut self, text: &str) {
+        for piece in text.split_inclusive('\n') {
+            self.push_segment(None, 0, 0);
+            self.push_text(piece);
+        }
+    }
+
+    /// Finish building, returning the text and its source map.
+    pub fn finish(mut self) -> (String, SourceMap) {
+        self.push_segment(None, 0, 0);
+        (self.text, self.map)
+    }
+
+    fn push_segment(&mut self, file: Option<usize>, original_line: usize, original_column: usize) {
+        self.map.segments.push(SourceMapSegment {
+            output_offset: self.text.len(),
+            output_line: self.line,
+            output_column: self.column,
+            file,
+            original_line,
+            original_column,
+        });
+    }
+
+    fn push_text(&mut self, text: &str) {
+        self.text.push_str(text);
+        match text.rfind('\n') {
+            Some(newline) => {
+                self.line += text.matches('\n').count();
+                self.column = text.len() - newline;
+            }
+            None => self.column += text.len(),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Content-addressed storage of patched files.
+//!
+//! Files are stored in a directory named by their identity, so a patched root identity is enough
+//! to fetch it and everything it (transitively) includes. With the identity prefix and suffix of
+//! the `PatchOptions` the files were patched with, the (unsharded) directory is also an include
+//! root for them.
+
+use crate::{IdentityFormat, IncludeNodeGraph};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Options controlling the layout of a `ContentStore`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ContentStoreOptions {
+    /// Hash algorithm and encoding of the identities computed by `ContentStore::put`; identities
+    /// are verified with this format, or the format they describe when self-describing
+    pub identity: IdentityFormat,
+
+    /// Store each file in a subdirectory named by the first two characters of its identity, with
+    /// the rest of the identity as the file name (`ab/cdef…`), to keep directories small
+    pub sharded: bool,
+
+    /// Text before the identity in file names, which may include directories (e.g. `cas/`)
+    pub identity_prefix: String,
+
+    /// Text after the identity in file names (e.g. `.hlsli`)
+    pub identity_suffix: String,
+}
+
+/// A directory of files named by their identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentStore {
+    root: PathBuf,
+    options: ContentStoreOptions,
+}
+
+/// Distinguishes the temporary files of concurrent writes from the same process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl ContentStore {
+    /// Create a `ContentStore` in a directory, which is created when the first file is stored.
+    pub fn new(root: &Path, options: ContentStoreOptions) -> Self {
+        ContentStore {
+            root: root.to_path_buf(),
+            options,
+        }
+    }
+
+    /// Get the directory of the store.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Get the path a file is stored at; identities that couldn't have been produced by any
+    /// `IdentityEncoding` (such as ones containing path separators) are rejected.
+    pub fn path(&self, identity: &str) -> io::Result<PathBuf> {
+        if identity.len() < 3 || !identity.bytes().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid identity {:?}", identity),
+            ));
+        }
+        let (prefix, suffix) = (&self.options.identity_prefix, &self.options.identity_suffix);
+        Ok(if self.options.sharded {
+            self.root
+                .join(format!("{}{}", prefix, &identity[..2]))
+                .join(format!("{}{}", &identity[2..], suffix))
+        } else {
+            self.root.join(format!("{}{}{}", prefix, identity, suffix))
+        })
+    }
+
+    /// Check whether a file is in the store.
+    pub fn contains(&self, identity: &str) -> bool {
+        self.path(identity).is_ok_and(|path| path.is_file())
+    }
+
+    /// Check whether data has an identity, computed with the store's format or, for
+    /// self-describing identities, the format the identity describes.
+    pub fn verify(&self, identity: &str, data: &[u8]) -> bool {
+        if self.options.identity.compute(data) == identity {
+            return true;
+        }
+        match IdentityFormat::from_identity(identity) {
+            Some(format) if format != self.options.identity => format.compute(data) == identity,
+            _ => false,
+        }
+    }
+
+    /// Store data, returning its identity.
+    pub fn put(&self, data: &[u8]) -> io::Result<String> {
+        let identity = self.options.identity.compute(data);
+        self.write(&identity, data)?;
+        Ok(identity)
+    }
+
+    /// Store data under an identity (such as the patched identity of a node), failing with
+    /// `io::ErrorKind::InvalidData` if the data doesn't match it. Returns whether the file was
+    /// written, rather than already stored.
+    pub fn insert(&self, identity: &str, data: &[u8]) -> io::Result<bool> {
+        if !self.verify(identity, data) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data doesn't match identity {}", identity),
+            ));
+        }
+        self.write(identity, data)
+    }
+
+    /// Read a file from the store, failing with `io::ErrorKind::InvalidData` if its contents
+    /// don't match its identity (e.g. it was corrupted on disk).
+    pub fn get(&self, identity: &str) -> io::Result<Vec<u8>> {
+        let data = fs::read(self.path(identity)?)?;
+        if !self.verify(identity, &data) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("stored data doesn't match identity {}", identity),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Store the patched text of every node in a graph (see `traverse_patch_with`), returning the
+    /// number of files written.
+    pub fn publish_graph(&self, graph: &IncludeNodeGraph) -> io::Result<usize> {
+        let mut written = 0;
+        for node in graph.raw_nodes() {
+            let node = &node.weight.node;
+            if let Some(ref patched_identity) = node.patched_identity {
+                if self.insert(patched_identity, node.flattened.as_bytes())? {
+                    written += 1;
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    /// Write a file atomically, by writing a temporary file next to it and renaming it; readers
+    /// never see a partially written file.
+    fn write(&self, identity: &str, data: &[u8]) -> io::Result<bool> {
+        let path = self.path(identity)?;
+        if path.is_file() {
+            return Ok(false);
+        }
+        let dir = path.parent().unwrap_or(&self.root);
+        fs::create_dir_all(dir)?;
+
+        let temp_path = dir.join(format!(
+            ".{}.{}-{}.tmp",
+            identity,
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = fs::File::create(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp_path, &path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result.map(|_| true)
+    }
+}
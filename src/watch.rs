@@ -0,0 +1,186 @@
+//! Watching the files of a workspace for live reloading, enabled with the `watch` feature.
+//!
+//! The directories containing the files of an `IncludeWorkspace` are watched with `notify`.
+//! Bursts of edits (editors often write a file in several steps) are debounced, then the changed
+//! files are re-hashed incrementally and an event is emitted for every root whose identity
+//! changed, so only the affected pipelines need to be recompiled.
+
+use crate::{DiskSourceProvider, IncludeError, IncludeWorkspace};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Events emitted by a `WorkspaceWatcher`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The identity of a root changed.
+    RootIdentityChanged {
+        /// Canonical path of the root
+        root: PathBuf,
+
+        /// Identity before the change
+        old: Option<String>,
+
+        /// Identity after the change
+        new: Option<String>,
+    },
+
+    /// Files changed, but the graph couldn't be rebuilt (e.g. an include cycle was introduced);
+    /// the workspace keeps its previous structure.
+    Failed(IncludeError),
+}
+
+/// Watches every file in an `IncludeWorkspace`, keeping it up to date as files change on disk.
+pub struct WorkspaceWatcher {
+    workspace: IncludeWorkspace,
+    debounce: Duration,
+    watcher: RecommendedWatcher,
+    receiver: Receiver<notify::Result<notify::Event>>,
+
+    /// Directories being watched.
+    watched: HashSet<PathBuf>,
+
+    /// Files reported changed since the last update.
+    pending: HashSet<PathBuf>,
+
+    /// Time of the most recent file system event, while updates are pending.
+    last_event: Option<Instant>,
+}
+
+impl WorkspaceWatcher {
+    /// Start watching the files of a workspace (which should already be patched), waiting for
+    /// files to be quiet for the `debounce` duration before updating it.
+    pub fn new(workspace: IncludeWorkspace, debounce: Duration) -> notify::Result<Self> {
+        let (sender, receiver) = channel();
+        let watcher = notify::recommended_watcher(sender)?;
+        let mut workspace_watcher = WorkspaceWatcher {
+            workspace,
+            debounce,
+            watcher,
+            receiver,
+            watched: HashSet::new(),
+            pending: HashSet::new(),
+            last_event: None,
+        };
+        workspace_watcher.watch_directories()?;
+        Ok(workspace_watcher)
+    }
+
+    /// Get the watched workspace.
+    pub fn workspace(&self) -> &IncludeWorkspace {
+        &self.workspace
+    }
+
+    /// Stop watching, returning the workspace.
+    pub fn into_workspace(self) -> IncludeWorkspace {
+        self.workspace
+    }
+
+    /// Update the workspace if files changed and have since been quiet for the debounce
+    /// duration, without blocking.
+    pub fn poll(&mut self) -> Vec<WatchEvent> {
+        self.wait(Duration::from_secs(0))
+    }
+
+    /// Block until files changed and have been quiet for the debounce duration (or until the
+    /// timeout elapses), then update the workspace and return the resulting events.
+    pub fn wait(&mut self, timeout: Duration) -> Vec<WatchEvent> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            let wait_until = match self.last_event {
+                Some(last_event) => deadline.min(last_event + self.debounce),
+                None => deadline,
+            };
+            match self
+                .receiver
+                .recv_timeout(wait_until.saturating_duration_since(now))
+            {
+                Ok(Ok(event)) => {
+                    // Reads (including our own) don't change anything.
+                    if let EventKind::Access(_) = event.kind {
+                        continue;
+                    }
+                    self.pending.extend(event.paths);
+                    self.last_event = Some(Instant::now());
+                }
+                Ok(Err(error)) => {
+                    log::warn!("File watcher error: {}", error);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let quiet = self
+                        .last_event
+                        .map(|last_event| last_event + self.debounce <= Instant::now());
+                    if quiet == Some(true) {
+                        return self.update();
+                    }
+                    if Instant::now() >= deadline {
+                        return Vec::new();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Vec::new(),
+            }
+        }
+    }
+
+    /// Re-hash the files that changed and watch the directories of any new files.
+    fn update(&mut self) -> Vec<WatchEvent> {
+        let provider = DiskSourceProvider;
+        self.last_event = None;
+
+        // Modification times can be too coarse to notice quick successive writes, so files
+        // reported by the watcher are always compared.
+        let mut changed = self.workspace.detect_changes(&provider);
+        for path in self.pending.drain() {
+            if self.workspace.node(&path, &provider).is_some() && !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+
+        let events = match self.workspace.rehash(&changed, &provider) {
+            Ok(changes) => changes
+                .into_iter()
+                .map(|change| WatchEvent::RootIdentityChanged {
+                    root: change.root,
+                    old: change.old,
+                    new: change.new,
+                })
+                .collect(),
+            Err(error) => vec![WatchEvent::Failed(error)],
+        };
+        if let Err(error) = self.watch_directories() {
+            log::warn!("Failed to watch directories: {}", error);
+        }
+        events
+    }
+
+    /// Watch the directory of every file in the workspace, along with the include search paths
+    /// (where missing includes may be created). Directories are watched rather than files so
+    /// that files replaced by renaming (as many editors save) are still noticed.
+    fn watch_directories(&mut self) -> notify::Result<()> {
+        let graph = self.workspace.graph();
+        let mut directories = graph
+            .raw_nodes()
+            .iter()
+            .filter_map(|node| match node.weight.missing {
+                Some(ref missing) => missing.includer.parent(),
+                None => node.weight.node.include_file.parent(),
+            })
+            .map(Path::to_path_buf)
+            .collect::<Vec<PathBuf>>();
+        directories.push(self.workspace.working_dir().to_path_buf());
+        directories.extend(self.workspace.build_options().include_dirs.iter().cloned());
+
+        for directory in directories {
+            if self.watched.contains(&directory) || !directory.is_dir() {
+                continue;
+            }
+            self.watcher
+                .watch(&directory, RecursiveMode::NonRecursive)?;
+            self.watched.insert(directory);
+        }
+        Ok(())
+    }
+}
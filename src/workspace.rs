@@ -0,0 +1,393 @@
+//! Include graphs shared across many entry points.
+//!
+//! A project usually compiles many shaders that include the same headers. An `IncludeWorkspace`
+//! builds all of them into a single graph, so each shared header is read, parsed and patched once,
+//! and answers queries across the whole set of roots. When files change, only the changed files
+//! and the files including them are patched again.
+
+use crate::{
+    build_graph, get_root_nodes, patch_graph, reload_node, traverse_build_with, BuildOptions,
+    FileStamp, IncludeError, IncludeNode, IncludeNodeGraph, MissingInclude, PatchCache,
+    PatchOptions, PrefetchedFile, SourceProvider,
+};
+use petgraph::prelude::*;
+use petgraph::visit::{Reversed, Walker};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// An include graph with many roots, sharing nodes and identities between them.
+#[derive(Debug, Clone)]
+pub struct IncludeWorkspace {
+    graph: IncludeNodeGraph,
+    working_dir: PathBuf,
+    build_options: BuildOptions,
+    patch_options: PatchOptions,
+
+    /// Patched text of every file, keyed by the identities of its includes.
+    cache: PatchCache,
+
+    /// Modification time and size of every file when it was last read.
+    stamps: HashMap<PathBuf, FileStamp>,
+}
+
+/// The identity of a root before and after files changed.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct RootIdentityChange {
+    /// Canonical path of the root
+    pub root: PathBuf,
+
+    /// Identity before the change (`None` if the workspace wasn't patched)
+    pub old: Option<String>,
+
+    /// Identity after the change
+    pub new: Option<String>,
+}
+
+impl IncludeWorkspace {
+    /// Create an empty `IncludeWorkspace`.
+    pub fn new(
+        working_dir: &Path,
+        build_options: BuildOptions,
+        patch_options: PatchOptions,
+    ) -> Self {
+        IncludeWorkspace {
+            graph: IncludeNodeGraph::new(),
+            working_dir: working_dir.to_path_buf(),
+            build_options,
+            patch_options,
+            cache: PatchCache::new(),
+            stamps: HashMap::new(),
+        }
+    }
+
+    /// Get the include graph of every root.
+    pub fn graph(&self) -> &IncludeNodeGraph {
+        &self.graph
+    }
+
+    /// Get the working directory the roots are built from.
+    pub fn working_dir(&self) -> &Path {
+        &self.working_dir
+    }
+
+    /// Get the options used to build the graph.
+    pub fn build_options(&self) -> &BuildOptions {
+        &self.build_options
+    }
+
+    /// Get the options used to patch the graph.
+    pub fn patch_options(&self) -> &PatchOptions {
+        &self.patch_options
+    }
+
+    /// Add an entry point, building any files it includes that aren't already in the graph.
+    ///
+    /// The workspace needs to be patched again afterwards for the identities to cover the new
+    /// root.
+    pub fn add_root(
+        &mut self,
+        include_file: &Path,
+        provider: &dyn SourceProvider,
+    ) -> Result<NodeIndex, IncludeError> {
+        let root_node = traverse_build_with(
+            &mut self.graph,
+            &self.working_dir,
+            include_file,
+            0,
+            &self.build_options,
+            provider,
+        )?;
+        self.record_stamps(provider);
+        Ok(root_node)
+    }
+
+    /// Get the root nodes of the workspace.
+    pub fn roots(&self) -> Vec<NodeIndex> {
+        get_root_nodes(&self.graph)
+    }
+
+    /// Patch in Merkle identities for every root, patching shared files only once and reusing
+    /// the patched text of files whose includes haven't changed since the last patch.
+    pub fn patch(&mut self, provider: &dyn SourceProvider) {
+        let roots = self.roots();
+        patch_graph(
+            &mut self.graph,
+            &roots,
+            &self.patch_options,
+            provider,
+            Some(&mut self.cache),
+        );
+
+        // Only keep the patched text matching the current graph.
+        let identities = self
+            .graph
+            .raw_nodes()
+            .iter()
+            .filter(|node| node.weight.missing.is_none())
+            .filter_map(|node| {
+                let node = &node.weight.node;
+                Some((node.include_file.as_path(), node.patched_identity.as_ref()?))
+            })
+            .collect::<HashMap<&Path, &String>>();
+        self.cache.retain(|(include_file, _), patched| {
+            identities.get(include_file.as_path()) == Some(&&patched.patched_identity)
+        });
+    }
+
+    /// Get the node of a file in the workspace, looking the file up by its canonical path.
+    pub fn node(&self, include_file: &Path, provider: &dyn SourceProvider) -> Option<NodeIndex> {
+        let include_file = provider
+            .canonicalize(include_file)
+            .unwrap_or_else(|| include_file.to_path_buf());
+        self.graph.node_indices().find(|node_index| {
+            let weight = &self.graph[*node_index];
+            weight.missing.is_none() && weight.node.include_file == include_file
+        })
+    }
+
+    /// Get the identity of a root: the salted identity when `PatchOptions::context` is set,
+    /// otherwise the patched identity. Returns `None` if the file isn't a root or the workspace
+    /// hasn't been patched.
+    pub fn root_identity(
+        &self,
+        include_file: &Path,
+        provider: &dyn SourceProvider,
+    ) -> Option<&str> {
+        let node_index = self.node(include_file, provider)?;
+        if !self.graph[node_index].is_root {
+            return None;
+        }
+        node_identity(&self.graph[node_index].node).map(String::as_str)
+    }
+
+    /// Get the roots that include a file, directly or through other files; a root counts as
+    /// including itself.
+    pub fn roots_including(
+        &self,
+        include_file: &Path,
+        provider: &dyn SourceProvider,
+    ) -> Vec<&IncludeNode> {
+        let node_index = match self.node(include_file, provider) {
+            Some(node_index) => node_index,
+            None => return Vec::new(),
+        };
+        let includers = Dfs::new(Reversed(&self.graph), node_index)
+            .iter(Reversed(&self.graph))
+            .collect::<HashSet<NodeIndex>>();
+        self.roots()
+            .into_iter()
+            .filter(|root| includers.contains(root))
+            .map(|root| &self.graph[root].node)
+            .collect()
+    }
+
+    /// Find the files that changed since they were last read.
+    ///
+    /// Files whose modification time and size (see `SourceProvider::stamp`) are unchanged are
+    /// skipped; the contents of the others are compared. Includers of unresolved directives are
+    /// also reported when a file matching the directive has since been created.
+    pub fn detect_changes(&mut self, provider: &dyn SourceProvider) -> Vec<PathBuf> {
+        let mut changed: Vec<PathBuf> = Vec::new();
+        for node in self.graph.raw_nodes() {
+            let weight = &node.weight;
+            if let Some(ref missing) = weight.missing {
+                if self.resolves(missing, provider) && !changed.contains(&missing.includer) {
+                    changed.push(missing.includer.clone());
+                }
+                continue;
+            }
+
+            let include_file = &weight.node.include_file;
+            let stamp = provider.stamp(include_file);
+            if stamp.is_some() && self.stamps.get(include_file) == stamp.as_ref() {
+                continue;
+            }
+            let include_text = weight
+                .node
+                .data_as_string_from(provider, self.build_options.normalize_endings);
+            if weight.node.text.as_deref() == Some(include_text.as_str())
+                && weight.node.text_normalized == self.build_options.normalize_endings
+            {
+                // Touched but unchanged.
+                if let Some(stamp) = stamp {
+                    self.stamps.insert(include_file.clone(), stamp);
+                }
+            } else if !changed.contains(include_file) {
+                changed.push(include_file.clone());
+            }
+        }
+        changed
+    }
+
+    /// Detect the files that changed (see `detect_changes`) and update the workspace.
+    pub fn rehash_changed(
+        &mut self,
+        provider: &dyn SourceProvider,
+    ) -> Result<Vec<RootIdentityChange>, IncludeError> {
+        let changed = self.detect_changes(provider);
+        self.rehash(&changed, provider)
+    }
+
+    /// Re-read a set of changed files and patch them and the files including them again,
+    /// returning the roots whose identity changed.
+    ///
+    /// Cached patched text is reused for every file that didn't change and whose includes kept
+    /// the same identities. If a changed file now includes different files (or was created or
+    /// deleted), the structure of the graph is rebuilt from the text already loaded, reading
+    /// only new files. If the rebuild fails (e.g. the change introduces an include cycle), the
+    /// error is returned and the workspace is left as it was, so the changed files are detected
+    /// (and the rebuild attempted) again.
+    pub fn rehash(
+        &mut self,
+        changed: &[PathBuf],
+        provider: &dyn SourceProvider,
+    ) -> Result<Vec<RootIdentityChange>, IncludeError> {
+        // Rebuilding the graph can reorder the roots, so identities are matched by path.
+        let mut old_identities = self
+            .root_identities()
+            .into_iter()
+            .collect::<HashMap<PathBuf, Option<String>>>();
+
+        let mut changed_files = HashSet::new();
+        let mut restructured = false;
+        let mut previous = Vec::new();
+        for include_file in changed {
+            let canonical_file = provider.canonicalize(include_file);
+            let node_index = match self.node(include_file, provider) {
+                Some(node_index) => node_index,
+                None => {
+                    // A deleted file, or a new file which could resolve a missing include.
+                    let has_missing = self
+                        .graph
+                        .raw_nodes()
+                        .iter()
+                        .any(|node| node.weight.missing.is_some());
+                    restructured |= canonical_file.is_none() || has_missing;
+                    continue;
+                }
+            };
+            if canonical_file.is_none() {
+                restructured = true;
+            }
+            let has_missing = self
+                .graph
+                .neighbors_directed(node_index, Direction::Outgoing)
+                .any(|neighbor| self.graph[neighbor].missing.is_some());
+            previous.push((node_index, self.graph[node_index].clone()));
+            match reload_node(&mut self.graph, node_index, &self.build_options, provider) {
+                Some(node_restructured) => {
+                    restructured |= node_restructured;
+                    changed_files.insert(self.graph[node_index].node.include_file.clone());
+                }
+                None => restructured |= has_missing,
+            }
+        }
+
+        if restructured {
+            if let Err(error) = self.rebuild(provider) {
+                for (node_index, weight) in previous {
+                    self.graph[node_index] = weight;
+                }
+                return Err(error);
+            }
+        }
+        self.cache
+            .retain(|(include_file, _), _| !changed_files.contains(include_file));
+        self.stamps
+            .retain(|include_file, _| !changed_files.contains(include_file));
+        self.record_stamps(provider);
+        self.patch(provider);
+
+        Ok(self
+            .root_identities()
+            .into_iter()
+            .filter_map(|(root, new)| {
+                let old = old_identities.remove(&root).flatten();
+                if old == new {
+                    return None;
+                }
+                Some(RootIdentityChange { root, old, new })
+            })
+            .collect())
+    }
+
+    /// Rebuild the graph structure for every root, reusing the text of the files already loaded.
+    fn rebuild(&mut self, provider: &dyn SourceProvider) -> Result<(), IncludeError> {
+        let mut prefetched = self
+            .graph
+            .raw_nodes()
+            .iter()
+            .filter(|node| node.weight.missing.is_none())
+            .map(|node| {
+                let node = &node.weight.node;
+                let include_text = node.source_text(provider, self.build_options.normalize_endings);
+                let prefetched = PrefetchedFile {
+                    text: include_text.to_string(),
+                    includes: None,
+                };
+                (node.include_file.clone(), prefetched)
+            })
+            .collect::<HashMap<PathBuf, PrefetchedFile>>();
+        let mut graph = IncludeNodeGraph::new();
+        for root_node in self.roots() {
+            build_graph(
+                &mut graph,
+                &self.working_dir,
+                &self.graph[root_node].node.include_file,
+                0,
+                &self.build_options,
+                provider,
+                &mut prefetched,
+            )?;
+        }
+        self.graph = graph;
+        Ok(())
+    }
+
+    /// Get the canonical path and identity of every root.
+    fn root_identities(&self) -> Vec<(PathBuf, Option<String>)> {
+        self.roots()
+            .into_iter()
+            .map(|root_node| {
+                let root = &self.graph[root_node].node;
+                (root.include_file.clone(), node_identity(root).cloned())
+            })
+            .collect()
+    }
+
+    /// Record the stamp of every file that doesn't have one yet.
+    fn record_stamps(&mut self, provider: &dyn SourceProvider) {
+        for node in self.graph.raw_nodes() {
+            let include_file = &node.weight.node.include_file;
+            if node.weight.missing.is_some() || self.stamps.contains_key(include_file) {
+                continue;
+            }
+            if let Some(stamp) = provider.stamp(include_file) {
+                self.stamps.insert(include_file.clone(), stamp);
+            }
+        }
+    }
+
+    /// Check whether an unresolved include directive now resolves, next to its includer or on
+    /// the include search paths.
+    fn resolves(&self, missing: &MissingInclude, provider: &dyn SourceProvider) -> bool {
+        missing
+            .includer
+            .parent()
+            .into_iter()
+            .chain(std::iter::once(self.working_dir.as_path()))
+            .chain(self.build_options.include_dirs.iter().map(PathBuf::as_path))
+            .any(|include_dir| {
+                provider
+                    .canonicalize(&include_dir.join(&missing.spelled_path))
+                    .is_some()
+            })
+    }
+}
+
+/// Get the salted identity of a node if set, otherwise its patched identity.
+fn node_identity(node: &IncludeNode) -> Option<&String> {
+    node.salted_identity
+        .as_ref()
+        .or(node.patched_identity.as_ref())
+}
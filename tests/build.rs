@@ -0,0 +1,90 @@
+use include_merkle::*;
+use std::path::Path;
+
+fn cycle_provider() -> MemorySourceProvider {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert("/shaders/A.hlsl", "#include \"B.hlsli\"\n");
+    provider.insert("/shaders/B.hlsli", "#include \"C.hlsli\"\n");
+    provider.insert("/shaders/C.hlsli", "#include \"B.hlsli\"\n");
+    provider.insert("/shaders/D.hlsl", "#include \"C.hlsli\"\n");
+    provider.insert("/shaders/E.hlsl", "float e;\n");
+    provider
+}
+
+#[test]
+fn failed_build_leaves_graph_unchanged() {
+    let provider = cycle_provider();
+    let mut graph = IncludeNodeGraph::new();
+    let build = |graph: &mut IncludeNodeGraph, file: &str| {
+        traverse_build_with(
+            graph,
+            Path::new("/shaders"),
+            Path::new(file),
+            0,
+            &BuildOptions::default(),
+            &provider,
+        )
+    };
+
+    build(&mut graph, "/shaders/E.hlsl").expect("failed to build include graph");
+    let before = (graph.node_count(), graph.edge_count());
+
+    let error = build(&mut graph, "/shaders/A.hlsl").unwrap_err();
+    assert!(matches!(error, IncludeError::Cycle { .. }));
+    assert_eq!((graph.node_count(), graph.edge_count()), before);
+    assert_eq!(get_root_nodes(&graph).len(), 1);
+
+    // The files of the failed build aren't reused without their includes.
+    let error = build(&mut graph, "/shaders/D.hlsl").unwrap_err();
+    assert!(matches!(error, IncludeError::Cycle { .. }));
+    assert_eq!((graph.node_count(), graph.edge_count()), before);
+}
+
+#[test]
+fn workspace_add_root_after_failed_root() {
+    let provider = cycle_provider();
+    let mut workspace = IncludeWorkspace::new(
+        Path::new("/shaders"),
+        BuildOptions::default(),
+        PatchOptions::default(),
+    );
+    assert!(workspace
+        .add_root(Path::new("/shaders/A.hlsl"), &provider)
+        .is_err());
+    assert!(workspace
+        .add_root(Path::new("/shaders/D.hlsl"), &provider)
+        .is_err());
+    assert_eq!(workspace.graph().node_count(), 0);
+}
+
+#[test]
+fn reload_restructured_file() {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert("/shaders/Root.hlsl", "#include \"A.hlsli\"\n");
+    provider.insert("/shaders/A.hlsli", "float a;\n");
+    provider.insert("/shaders/B.hlsli", "float b;\n");
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        Path::new("/shaders"),
+        Path::new("/shaders/Root.hlsl"),
+        0,
+        &BuildOptions::default(),
+        &provider,
+    )
+    .expect("failed to build include graph");
+
+    // The new directive is at a different offset, after multi-byte characters.
+    let text = "// é comment that shifts ranges\n#include \"B.hlsli\"\n";
+    provider.insert("/shaders/Root.hlsl", text);
+    let reloaded = reload_changed_files(&mut graph, &BuildOptions::default(), &provider);
+    assert_eq!(reloaded.changed, [root_node]);
+    assert_eq!(reloaded.restructured, [root_node]);
+    assert!(graph[root_node].includes.is_empty());
+
+    // The directives are left as they are, rather than patched at the old offsets.
+    traverse_patch_with(&mut graph, root_node, &PatchOptions::default(), &provider);
+    let root = &graph[root_node].node;
+    assert_eq!(Some(root.flattened.as_str()), root.text.as_deref());
+    assert!(root.flattened.contains("#include \"B.hlsli\""));
+}
@@ -0,0 +1,109 @@
+use include_merkle::*;
+use std::fs;
+
+#[test]
+fn build_script_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    let shaders = dir.join("shaders");
+    let include = dir.join("include");
+    let output_dir = dir.join("out");
+    fs::create_dir_all(&shaders).unwrap();
+    fs::create_dir_all(&include).unwrap();
+    fs::write(
+        shaders.join("BlitCS.hlsl"),
+        "#include \"Common.hlsli\"\n#include \"Missing.hlsli\"\nfloat b;\n",
+    )
+    .unwrap();
+    fs::write(shaders.join("Common.hlsli"), "float c;\n").unwrap();
+
+    let options = BuildScriptOptions {
+        build: BuildOptions {
+            include_dirs: vec![include.clone()],
+            ..Default::default()
+        },
+        patch: PatchOptions {
+            identity_prefix: "cas/".to_string(),
+            identity_suffix: ".hlsli".to_string(),
+            ..Default::default()
+        },
+        flatten: Some(FlattenOptions::default()),
+        output_dir: Some(output_dir.clone()),
+    };
+    let output = build_script(&shaders, &[shaders.join("BlitCS.hlsl")], &options)
+        .expect("failed to run build script");
+
+    // Every file in the graph, and every directory the missing include could be created in.
+    assert_eq!(
+        output.rerun_if_changed,
+        [
+            shaders.join("BlitCS.hlsl"),
+            shaders.join("Common.hlsli"),
+            shaders.clone(),
+            include
+        ]
+    );
+
+    // Patched files are named like the patched include directives, relative to the output.
+    let entry_point = &output.entry_points[0];
+    assert_eq!(entry_point.const_name, "BLIT_CS");
+    assert_eq!(
+        entry_point.patched_file,
+        output_dir.join(format!("cas/{}.hlsli", entry_point.identity))
+    );
+    let patched = fs::read_to_string(&entry_point.patched_file).unwrap();
+    let common = patched
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("#include \""))
+        .and_then(|line| line.strip_suffix('"'))
+        .unwrap();
+    assert!(common.starts_with("cas/"));
+    assert_eq!(
+        fs::read_to_string(output_dir.join(common)).unwrap(),
+        "float c;\n"
+    );
+    assert!(patched.contains("#include \"Missing.hlsli\"\n"));
+
+    let (flattened_identity, flattened_file) = entry_point.flattened.clone().unwrap();
+    assert_eq!(
+        flattened_file,
+        output_dir.join(format!("{}.hlsl", flattened_identity))
+    );
+    let flattened = fs::read_to_string(&flattened_file).unwrap();
+    assert!(flattened.contains("float c;\n"));
+    assert_eq!(compute_identity(flattened.as_bytes()), flattened_identity);
+
+    assert_eq!(output.module_file, output_dir.join("include_merkle.rs"));
+    let module = fs::read_to_string(&output.module_file).unwrap();
+    for line in [
+        format!("pub const BLIT_CS: &str = {:?};", entry_point.identity),
+        format!(
+            "pub const BLIT_CS_PATCHED: &str = include_str!({:?});",
+            entry_point.patched_file
+        ),
+        format!(
+            "pub const BLIT_CS_FLATTENED: &str = include_str!({:?});",
+            flattened_file
+        ),
+        format!(
+            "pub const BLIT_CS_FLATTENED_IDENTITY: &str = {:?};",
+            flattened_identity
+        ),
+    ] {
+        assert!(module.contains(&line), "{}", line);
+    }
+}
+
+#[test]
+fn build_script_rejects_unnamed_entry_point() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    fs::write(dir.join("-.hlsl"), "float a;\n").unwrap();
+    let options = BuildScriptOptions {
+        output_dir: Some(dir.join("out")),
+        ..Default::default()
+    };
+    let error = build_script(&dir, &[dir.join("-.hlsl")], &options).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,213 @@
+#![cfg(feature = "cli")]
+
+use include_merkle::*;
+use petgraph::graph::NodeIndex;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Run the command-line binary, returning its standard output.
+fn include_merkle(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_include-merkle"))
+        .args(args)
+        .output()
+        .expect("failed to run include-merkle");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Write a root file including a header, which includes another header.
+fn write_shaders(dir: &Path) {
+    fs::write(
+        dir.join("Root.hlsl"),
+        "#include \"Common.hlsli\"\nfloat a;\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("Common.hlsli"),
+        "#include <Inner.hlsli>\nfloat c;\n",
+    )
+    .unwrap();
+    fs::write(dir.join("Inner.hlsli"), "float d;\n").unwrap();
+}
+
+/// Build and patch the root file with the library.
+fn patch_root(dir: &Path, options: &PatchOptions) -> (IncludeNodeGraph, NodeIndex) {
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        dir,
+        &dir.join("Root.hlsl"),
+        0,
+        &BuildOptions::default(),
+        &DiskSourceProvider,
+    )
+    .expect("failed to build include graph");
+    traverse_patch_with(&mut graph, root_node, options, &DiskSourceProvider);
+    (graph, root_node)
+}
+
+#[test]
+fn hash() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    write_shaders(&dir);
+    let root = dir.join("Root.hlsl");
+
+    let (graph, root_node) = patch_root(&dir, &PatchOptions::default());
+    let identity = graph[root_node].node.patched_identity.clone().unwrap();
+    assert_eq!(
+        include_merkle(&["hash", root.to_str().unwrap()]),
+        format!("{}\n", identity)
+    );
+
+    let options = PatchOptions {
+        identity: IdentityFormat {
+            encoding: IdentityEncoding::Hex,
+            self_describing: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (graph, root_node) = patch_root(&dir, &options);
+    let identity = graph[root_node].node.patched_identity.clone().unwrap();
+    assert_eq!(
+        include_merkle(&[
+            "hash",
+            root.to_str().unwrap(),
+            "--encoding",
+            "hex",
+            "--self-describing"
+        ]),
+        format!("{}\n", identity)
+    );
+}
+
+#[test]
+fn hash_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_include-merkle"))
+        .args(["hash", dir.path().join("Missing.hlsl").to_str().unwrap()])
+        .output()
+        .expect("failed to run include-merkle")
+        .status;
+    assert!(!status.success());
+}
+
+#[test]
+fn patch() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    let output = dir.join("out");
+    write_shaders(&dir);
+
+    let root_identity = include_merkle(&[
+        "patch",
+        dir.join("Root.hlsl").to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+
+    // Every patched file is stored, named by its identity.
+    let (graph, root_node) = patch_root(&dir, &PatchOptions::default());
+    assert_eq!(
+        root_identity,
+        format!(
+            "{}\n",
+            graph[root_node].node.patched_identity.as_ref().unwrap()
+        )
+    );
+    assert_eq!(fs::read_dir(&output).unwrap().count(), graph.node_count());
+    for node in graph.raw_nodes() {
+        let node = &node.weight.node;
+        let identity = node.patched_identity.as_ref().unwrap();
+        assert_eq!(
+            fs::read_to_string(output.join(identity)).unwrap(),
+            node.flattened
+        );
+    }
+
+    // Sharded stores split each identity after its first two characters.
+    let sharded = dir.join("sharded");
+    let root_identity = include_merkle(&[
+        "patch",
+        dir.join("Root.hlsl").to_str().unwrap(),
+        "-o",
+        sharded.to_str().unwrap(),
+        "--sharded",
+    ]);
+    let root_identity = root_identity.trim();
+    assert!(sharded
+        .join(&root_identity[..2])
+        .join(&root_identity[2..])
+        .is_file());
+}
+
+#[test]
+fn deps() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    write_shaders(&dir);
+    let deps = include_merkle(&["deps", dir.join("Root.hlsl").to_str().unwrap()]);
+    assert_eq!(
+        deps.lines().collect::<Vec<&str>>(),
+        ["Root.hlsl", "Common.hlsli", "Inner.hlsli"]
+            .iter()
+            .map(|file| dir.join(file).to_str().unwrap().to_string())
+            .collect::<Vec<String>>()
+    );
+}
+
+#[test]
+fn patch_output_is_an_include_root() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    let shaders = dir.join("shaders");
+    let output = dir.join("out");
+    fs::create_dir_all(&shaders).unwrap();
+    write_shaders(&shaders);
+
+    let root_identity = include_merkle(&[
+        "patch",
+        shaders.join("Root.hlsl").to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+        "--identity-prefix",
+        "cas/",
+        "--identity-suffix",
+        ".hlsli",
+    ]);
+    let root_file = output.join(format!("cas/{}.hlsli", root_identity.trim()));
+
+    // Every patched include directive resolves to a file in the output.
+    let options = BuildOptions {
+        strict: true,
+        ..Default::default()
+    };
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        &output,
+        &root_file,
+        0,
+        &options,
+        &DiskSourceProvider,
+    )
+    .expect("failed to build include graph of the patch output");
+    assert_eq!(graph.node_count(), 3);
+
+    traverse_flatten_with(
+        &mut graph,
+        root_node,
+        &FlattenOptions::default(),
+        &DiskSourceProvider,
+    );
+    let flattened = &graph[root_node].node.flattened;
+    for code in ["float a;", "float c;", "float d;"] {
+        assert!(flattened.contains(code), "{}", code);
+    }
+}
@@ -0,0 +1,246 @@
+use include_merkle::*;
+use petgraph::graph::NodeIndex;
+use std::path::Path;
+
+fn nested_provider() -> MemorySourceProvider {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert(
+        "/shaders/Root.hlsl",
+        "float a;\n#include \"Common.hlsli\"\nfloat b;\n",
+    );
+    provider.insert(
+        "/shaders/Common.hlsli",
+        "#include \"Inner.hlsli\"\nfloat c;\n",
+    );
+    provider.insert("/shaders/Inner.hlsli", "float d;\n");
+    provider
+}
+
+fn flatten(
+    provider: &MemorySourceProvider,
+    options: &FlattenOptions,
+) -> (IncludeNodeGraph, NodeIndex, Vec<std::path::PathBuf>) {
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        Path::new("/shaders"),
+        Path::new("/shaders/Root.hlsl"),
+        0,
+        &BuildOptions::default(),
+        provider,
+    )
+    .expect("failed to build include graph");
+    let source_files = traverse_flatten_with(&mut graph, root_node, options, provider);
+    (graph, root_node, source_files)
+}
+
+#[test]
+fn flatten_c_line_directives() {
+    let options = FlattenOptions {
+        line_directives: LineDirectiveStyle::C,
+        ..Default::default()
+    };
+    let (graph, root_node, _) = flatten(&nested_provider(), &options);
+    assert_eq!(
+        graph[root_node].node.flattened,
+        "\
+float a;
+// EMBED-START - \"/shaders/Common.hlsli\"
+#line 1 \"/shaders/Common.hlsli\"
+// EMBED-START - \"/shaders/Inner.hlsli\"
+#line 1 \"/shaders/Inner.hlsli\"
+float d;
+
+// EMBED-FINISH - \"/shaders/Inner.hlsli\"
+#line 2 \"/shaders/Common.hlsli\"
+float c;
+
+// EMBED-FINISH - \"/shaders/Common.hlsli\"
+#line 3 \"/shaders/Root.hlsl\"
+float b;
+"
+    );
+}
+
+#[test]
+fn flatten_glsl_line_directives() {
+    let options = FlattenOptions {
+        line_directives: LineDirectiveStyle::Glsl,
+        ..Default::default()
+    };
+    let (graph, root_node, source_files) = flatten(&nested_provider(), &options);
+
+    // Source string numbers index the returned file list.
+    assert_eq!(
+        source_files,
+        [
+            Path::new("/shaders/Root.hlsl"),
+            Path::new("/shaders/Common.hlsli"),
+            Path::new("/shaders/Inner.hlsli")
+        ]
+    );
+    assert_eq!(
+        graph[root_node].node.flattened,
+        "\
+float a;
+// EMBED-START - \"/shaders/Common.hlsli\"
+#line 1 1
+// EMBED-START - \"/shaders/Inner.hlsli\"
+#line 1 2
+float d;
+
+// EMBED-FINISH - \"/shaders/Inner.hlsli\"
+#line 2 1
+float c;
+
+// EMBED-FINISH - \"/shaders/Common.hlsli\"
+#line 3 0
+float b;
+"
+    );
+}
+
+#[test]
+fn flatten_without_line_directives() {
+    let (graph, root_node, _) = flatten(&nested_provider(), &FlattenOptions::default());
+    assert!(!graph[root_node].node.flattened.contains("#line"));
+}
+
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let line_start = text[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        text[..offset].matches('\n').count() + 1,
+        offset - line_start + 1,
+    )
+}
+
+fn location(include_file: &str, line: usize, column: usize) -> Option<SourceLocation> {
+    Some(SourceLocation {
+        include_file: include_file.into(),
+        line,
+        column,
+    })
+}
+
+#[test]
+fn source_map_lookups() {
+    let options = FlattenOptions {
+        line_directives: LineDirectiveStyle::C,
+        ..Default::default()
+    };
+    let (graph, root_node, _) = flatten(&nested_provider(), &options);
+    let root = &graph[root_node].node;
+    let text = root.flattened.as_str();
+    let map = root.source_map.as_ref().expect("missing source map");
+    let lookup = |needle: &str| {
+        let offset = text.find(needle).expect("missing text");
+        let (line, column) = line_column(text, offset);
+        let location = map.lookup_offset(offset);
+        assert_eq!(map.lookup_line_column(line, column), location);
+        location
+    };
+
+    // Inside a nested include.
+    assert_eq!(lookup("float d;"), location("/shaders/Inner.hlsli", 1, 1));
+    assert_eq!(lookup("d;"), location("/shaders/Inner.hlsli", 1, 7));
+
+    // Straight after an include, in both the includer and the root.
+    assert_eq!(lookup("float c;"), location("/shaders/Common.hlsli", 2, 1));
+    assert_eq!(lookup("float b;"), location("/shaders/Root.hlsl", 3, 1));
+
+    // Generated text has no original location.
+    assert_eq!(lookup("// EMBED-FINISH"), None);
+    assert_eq!(lookup("#line 3"), None);
+}
+
+#[test]
+fn source_map_lookups_out_of_range() {
+    let (graph, root_node, _) = flatten(&nested_provider(), &FlattenOptions::default());
+    let root = &graph[root_node].node;
+    let text = root.flattened.as_str();
+    let map = root.source_map.as_ref().expect("missing source map");
+    let (last_line, _) = line_column(text, text.len() - 1);
+    assert_eq!(
+        map.lookup_line(last_line),
+        location("/shaders/Root.hlsl", 3, 1)
+    );
+
+    assert_eq!(map.lookup_offset(text.len()), None);
+    assert_eq!(map.lookup_offset(text.len() + 100), None);
+    assert_eq!(map.lookup_line(last_line + 1), None);
+    assert_eq!(map.lookup_line(last_line + 100), None);
+    assert_eq!(map.lookup_line_column(last_line, 100), None);
+    assert_eq!(map.lookup_line_column(0, 0), None);
+}
+
+fn flatten_included_twice(header: &str, include_once: bool) -> String {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert(
+        "/shaders/Root.hlsl",
+        "#include \"Once.hlsli\"\n#include \"Once.hlsli\"\nfloat a;\n",
+    );
+    provider.insert("/shaders/Once.hlsli", header);
+    let options = FlattenOptions {
+        include_once,
+        ..Default::default()
+    };
+    let (graph, root_node, _) = flatten(&provider, &options);
+    graph[root_node].node.flattened.clone()
+}
+
+#[test]
+fn include_once_pragma() {
+    let header = "#pragma once\nfloat once;\n";
+    assert_eq!(
+        flatten_included_twice(header, true),
+        "\
+// EMBED-START - \"/shaders/Once.hlsli\"
+
+float once;
+
+// EMBED-FINISH - \"/shaders/Once.hlsli\"
+
+float a;
+"
+    );
+    assert_eq!(
+        flatten_included_twice(header, false)
+            .matches("float once;")
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn include_once_guard() {
+    let header = "#ifndef ONCE_HLSLI\n#define ONCE_HLSLI\nfloat once;\n#endif\n";
+    assert_eq!(
+        flatten_included_twice(header, true),
+        "\
+// EMBED-START - \"/shaders/Once.hlsli\"
+#ifndef ONCE_HLSLI
+#define ONCE_HLSLI
+float once;
+#endif
+
+// EMBED-FINISH - \"/shaders/Once.hlsli\"
+
+float a;
+"
+    );
+    assert_eq!(
+        flatten_included_twice(header, false)
+            .matches("float once;")
+            .count(),
+        2
+    );
+
+    // Code after the `#endif` means the file isn't guarded.
+    let header = "#ifndef ONCE_HLSLI\n#define ONCE_HLSLI\n#endif\nfloat once;\n";
+    assert_eq!(
+        flatten_included_twice(header, true)
+            .matches("float once;")
+            .count(),
+        2
+    );
+}
@@ -0,0 +1,112 @@
+#![cfg(feature = "parallel")]
+
+use include_merkle::*;
+use petgraph::graph::NodeIndex;
+use std::path::{Path, PathBuf};
+
+fn build_serial(
+    working_dir: &Path,
+    include_file: &Path,
+    options: &BuildOptions,
+    provider: &dyn SourceProvider,
+) -> (IncludeNodeGraph, NodeIndex) {
+    let mut graph = IncludeNodeGraph::new();
+    let root_node =
+        traverse_build_with(&mut graph, working_dir, include_file, 0, options, provider)
+            .expect("failed to build include graph");
+    traverse_patch_with(&mut graph, root_node, &PatchOptions::default(), provider);
+    (graph, root_node)
+}
+
+fn build_parallel(
+    working_dir: &Path,
+    include_file: &Path,
+    options: &BuildOptions,
+    provider: &dyn SourceProvider,
+) -> (IncludeNodeGraph, NodeIndex) {
+    let mut graph = IncludeNodeGraph::new();
+    let root_node =
+        traverse_build_parallel(&mut graph, working_dir, include_file, 0, options, provider)
+            .expect("failed to build include graph");
+    traverse_patch_parallel(&mut graph, root_node, &PatchOptions::default(), provider);
+    (graph, root_node)
+}
+
+fn assert_identical(
+    serial: &(IncludeNodeGraph, NodeIndex),
+    parallel: &(IncludeNodeGraph, NodeIndex),
+) {
+    let ((serial, serial_root), (parallel, parallel_root)) = (serial, parallel);
+    assert_eq!(serial_root, parallel_root);
+    let nodes = |graph: &IncludeNodeGraph| {
+        graph
+            .raw_nodes()
+            .iter()
+            .map(|node| node.weight.clone())
+            .collect::<Vec<IncludeNodeWeight>>()
+    };
+    let edges = |graph: &IncludeNodeGraph| {
+        graph
+            .raw_edges()
+            .iter()
+            .map(|edge| (edge.source(), edge.target(), edge.weight))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(nodes(serial), nodes(parallel));
+    assert_eq!(edges(serial), edges(parallel));
+}
+
+#[test]
+fn parallel_matches_serial_for_examples() {
+    let working_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("examples/shaders")
+        .canonicalize()
+        .unwrap();
+    let mut roots = std::fs::read_dir(&working_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "hlsl")
+        })
+        .collect::<Vec<PathBuf>>();
+    roots.sort();
+    assert!(!roots.is_empty());
+
+    let options = BuildOptions::default();
+    for root in &roots {
+        let serial = build_serial(&working_dir, root, &options, &DiskSourceProvider);
+        let parallel = build_parallel(&working_dir, root, &options, &DiskSourceProvider);
+        assert_identical(&serial, &parallel);
+    }
+}
+
+#[test]
+fn parallel_resolves_quoted_includes_next_to_includers_first() {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert("/shaders/Root.hlsl", "#include \"sub/Mid.hlsli\"\n");
+    provider.insert("/shaders/sub/Mid.hlsli", "#include \"Common.hlsli\"\n");
+    provider.insert("/shaders/Common.hlsli", "float includer_dir;\n");
+    provider.insert("/include/Common.hlsli", "float working_dir;\n");
+    let options = BuildOptions::default();
+
+    let serial = build_serial(
+        Path::new("/include"),
+        Path::new("/shaders/Root.hlsl"),
+        &options,
+        &provider,
+    );
+    let parallel = build_parallel(
+        Path::new("/include"),
+        Path::new("/shaders/Root.hlsl"),
+        &options,
+        &provider,
+    );
+    assert_identical(&serial, &parallel);
+
+    let (graph, _) = parallel;
+    assert!(graph
+        .raw_nodes()
+        .iter()
+        .any(|node| node.weight.node.include_file == Path::new("/shaders/Common.hlsli")));
+}
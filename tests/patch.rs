@@ -0,0 +1,67 @@
+use include_merkle::*;
+use petgraph::graph::NodeIndex;
+use std::path::Path;
+
+const X_MACRO_SHADER: &str = "\
+#define ENTRY(name) float name;
+#include \"Entries.hlsli\"
+#undef ENTRY
+
+#define ENTRY(name) float4 name##_color;
+#include \"Entries.hlsli\"
+#undef ENTRY
+";
+
+const X_MACRO_ENTRIES: &str = "\
+ENTRY(first)
+ENTRY(second)
+";
+
+fn build_x_macro_graph(provider: &MemorySourceProvider) -> (IncludeNodeGraph, NodeIndex) {
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        Path::new("/shaders"),
+        Path::new("/shaders/XMacro.hlsl"),
+        0,
+        &BuildOptions::default(),
+        provider,
+    )
+    .expect("failed to build include graph");
+    (graph, root_node)
+}
+
+fn x_macro_provider() -> MemorySourceProvider {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert("/shaders/XMacro.hlsl", X_MACRO_SHADER);
+    provider.insert("/shaders/Entries.hlsli", X_MACRO_ENTRIES);
+    provider
+}
+
+#[test]
+fn patch_repeated_x_macro_include() {
+    let provider = x_macro_provider();
+    let (mut graph, root_node) = build_x_macro_graph(&provider);
+    traverse_patch_with(&mut graph, root_node, &PatchOptions::default(), &provider);
+
+    let entries_identity = compute_identity(X_MACRO_ENTRIES.as_bytes());
+    let expected = X_MACRO_SHADER.replace("Entries.hlsli", &entries_identity);
+    let root = &graph[root_node].node;
+    assert_eq!(root.flattened, expected);
+    assert_eq!(
+        root.patched_identity,
+        Some(compute_identity(expected.as_bytes()))
+    );
+}
+
+#[test]
+fn flatten_repeated_x_macro_include() {
+    let provider = x_macro_provider();
+    let (mut graph, root_node) = build_x_macro_graph(&provider);
+    traverse_flatten_with(&mut graph, root_node, &FlattenOptions::default(), &provider);
+
+    let flattened = &graph[root_node].node.flattened;
+    assert_eq!(flattened.matches("ENTRY(first)").count(), 2);
+    assert!(!flattened.contains("#include"));
+    assert!(flattened.ends_with("#undef ENTRY\n"));
+}
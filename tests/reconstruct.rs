@@ -0,0 +1,109 @@
+use include_merkle::*;
+use std::path::Path;
+
+fn patch_options() -> PatchOptions {
+    PatchOptions {
+        identity_prefix: "cas/".to_string(),
+        identity_suffix: ".hlsli".to_string(),
+        ..Default::default()
+    }
+}
+
+/// Build and patch a root, publish it to a store and return the root identity.
+fn publish(
+    provider: &MemorySourceProvider,
+    store: &ContentStore,
+    options: &PatchOptions,
+) -> (IncludeNodeGraph, String) {
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        Path::new("/shaders"),
+        Path::new("/shaders/Root.hlsl"),
+        0,
+        &BuildOptions::default(),
+        provider,
+    )
+    .expect("failed to build include graph");
+    traverse_patch_with(&mut graph, root_node, options, provider);
+    store.publish_graph(&graph).unwrap();
+    let root_identity = graph[root_node].node.patched_identity.clone().unwrap();
+
+    traverse_flatten_with(&mut graph, root_node, &FlattenOptions::default(), provider);
+    (graph, root_identity)
+}
+
+/// Remove the embed comments, which name the embedded files by path.
+fn without_embed_comments(text: &str) -> String {
+    text.lines()
+        .filter(|line| !line.starts_with("// EMBED-"))
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+#[test]
+fn reconstruct_round_trip() {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert(
+        "/shaders/Root.hlsl",
+        "#include \"Common.hlsli\"\n#include <Inner.hlsli>\nfloat a;\n",
+    );
+    provider.insert(
+        "/shaders/Common.hlsli",
+        "#include <Inner.hlsli>\nfloat c;\n",
+    );
+    provider.insert("/shaders/Inner.hlsli", "float d;\n");
+    let dir = tempfile::tempdir().unwrap();
+    let store = ContentStore::new(dir.path(), ContentStoreOptions::default());
+    let (graph, root_identity) = publish(&provider, &store, &patch_options());
+
+    let mut reconstruction =
+        reconstruct(&store, &root_identity, &patch_options()).expect("failed to reconstruct");
+    assert_eq!(reconstruction.files.len(), 3);
+    assert_eq!(
+        reconstruction.files[0],
+        Path::new(&format!("cas/{}.hlsli", root_identity))
+    );
+    assert!(graph_missing_includes(&reconstruction.graph).is_empty());
+
+    // Flattening the reconstruction gives the text of flattening the original files.
+    let root_node = get_root_nodes(&graph)[0];
+    let flattened = reconstruction.flatten(&FlattenOptions::default());
+    assert_eq!(
+        without_embed_comments(&flattened),
+        without_embed_comments(&graph[root_node].node.flattened)
+    );
+
+    let output = tempfile::tempdir().unwrap();
+    let root_file = reconstruction.materialize(output.path()).unwrap();
+    assert_eq!(root_file, output.path().join(&reconstruction.files[0]));
+    for file in &reconstruction.files {
+        assert!(output.path().join(file).is_file());
+    }
+}
+
+#[test]
+fn reconstruct_unresolved_include() {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert(
+        "/shaders/Root.hlsl",
+        "#include \"Missing\"\n#include \"Common.hlsli\"\nfloat a;\n",
+    );
+    provider.insert("/shaders/Common.hlsli", "float c;\n");
+    let dir = tempfile::tempdir().unwrap();
+    let store = ContentStore::new(dir.path(), ContentStoreOptions::default());
+    let options = PatchOptions::default();
+    let (_, root_identity) = publish(&provider, &store, &options);
+
+    // `Missing` is alphanumeric, but not an identity, so it's kept as a missing include.
+    assert_eq!(options.identity_from_path("Missing"), None);
+    let root = String::from_utf8(store.get(&root_identity).unwrap()).unwrap();
+    assert!(root.starts_with("#include \"Missing\"\n"));
+
+    let reconstruction =
+        reconstruct(&store, &root_identity, &options).expect("failed to reconstruct");
+    assert_eq!(reconstruction.files.len(), 2);
+    let missing = graph_missing_includes(&reconstruction.graph);
+    assert_eq!(missing.len(), 1);
+    assert_eq!(missing[0].spelled_path, Path::new("Missing"));
+}
@@ -0,0 +1,142 @@
+use include_merkle::*;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[test]
+fn store_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ContentStore::new(dir.path(), ContentStoreOptions::default());
+    let identity = store.put(b"float a;\n").unwrap();
+    assert_eq!(identity, compute_identity(b"float a;\n"));
+    assert_eq!(store.path(&identity).unwrap(), dir.path().join(&identity));
+    assert!(store.contains(&identity));
+    assert_eq!(store.get(&identity).unwrap(), b"float a;\n");
+
+    // Storing the same data again doesn't write it.
+    assert_eq!(store.put(b"float a;\n").unwrap(), identity);
+    assert!(!store.insert(&identity, b"float a;\n").unwrap());
+
+    // Self-describing identities are verified with the format they describe.
+    let format = IdentityFormat {
+        self_describing: true,
+        ..Default::default()
+    };
+    let described = format.compute(b"float b;\n");
+    assert!(store.insert(&described, b"float b;\n").unwrap());
+    assert_eq!(store.get(&described).unwrap(), b"float b;\n");
+
+    let error = store.get(&compute_identity(b"float c;\n")).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    let error = store.path("../a").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn store_corrupted_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ContentStore::new(dir.path(), ContentStoreOptions::default());
+    let identity = store.put(b"float a;\n").unwrap();
+    fs::write(store.path(&identity).unwrap(), "float b;\n").unwrap();
+    let error = store.get(&identity).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn store_insert_mismatch() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ContentStore::new(dir.path(), ContentStoreOptions::default());
+    let identity = compute_identity(b"float a;\n");
+    let error = store.insert(&identity, b"float b;\n").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(!store.contains(&identity));
+}
+
+#[test]
+fn store_sharded_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = ContentStoreOptions {
+        sharded: true,
+        ..Default::default()
+    };
+    let store = ContentStore::new(dir.path(), options);
+    let identity = store.put(b"float a;\n").unwrap();
+    let path = dir.path().join(&identity[..2]).join(&identity[2..]);
+    assert_eq!(store.path(&identity).unwrap(), path);
+    assert_eq!(fs::read(&path).unwrap(), b"float a;\n");
+
+    // The prefix and suffix wrap the shard and the file name.
+    let options = ContentStoreOptions {
+        sharded: true,
+        identity_prefix: "cas/".to_string(),
+        identity_suffix: ".hlsli".to_string(),
+        ..Default::default()
+    };
+    let store = ContentStore::new(dir.path(), options);
+    assert_eq!(
+        store.path(&identity).unwrap(),
+        dir.path()
+            .join("cas")
+            .join(&identity[..2])
+            .join(format!("{}.hlsli", &identity[2..]))
+    );
+}
+
+#[test]
+fn store_publish_graph() {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert(
+        "/shaders/Root.hlsl",
+        "#include \"Common.hlsli\"\nfloat a;\n",
+    );
+    provider.insert("/shaders/Common.hlsli", "float c;\n");
+    let mut graph = IncludeNodeGraph::new();
+    let root_node = traverse_build_with(
+        &mut graph,
+        Path::new("/shaders"),
+        Path::new("/shaders/Root.hlsl"),
+        0,
+        &BuildOptions::default(),
+        &provider,
+    )
+    .expect("failed to build include graph");
+    let patch_options = PatchOptions {
+        identity_prefix: "cas/".to_string(),
+        identity_suffix: ".hlsli".to_string(),
+        ..Default::default()
+    };
+    traverse_patch_with(&mut graph, root_node, &patch_options, &provider);
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = ContentStore::new(
+        dir.path(),
+        ContentStoreOptions {
+            identity_prefix: patch_options.identity_prefix.clone(),
+            identity_suffix: patch_options.identity_suffix.clone(),
+            ..Default::default()
+        },
+    );
+    assert_eq!(store.publish_graph(&graph).unwrap(), 2);
+    assert_eq!(store.publish_graph(&graph).unwrap(), 0);
+    for node in graph.raw_nodes() {
+        let node = &node.weight.node;
+        let identity = node.patched_identity.as_ref().unwrap();
+        assert_eq!(store.get(identity).unwrap(), node.flattened.as_bytes());
+    }
+
+    // The patched include directives resolve to the stored files.
+    let root = &graph[root_node].node;
+    let common = graph
+        .neighbors(root_node)
+        .map(|node| graph[node].node.patched_identity.clone().unwrap())
+        .next()
+        .unwrap();
+    let include_path = format!("cas/{}.hlsli", common);
+    assert!(root
+        .flattened
+        .contains(&format!("#include \"{}\"", include_path)));
+    assert_eq!(
+        fs::read_to_string(dir.path().join(include_path)).unwrap(),
+        "float c;\n"
+    );
+}
@@ -0,0 +1,98 @@
+#![cfg(feature = "watch")]
+
+use include_merkle::*;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const DEBOUNCE: Duration = Duration::from_millis(50);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn build_workspace(working_dir: &Path, roots: &[&str]) -> IncludeWorkspace {
+    let provider = DiskSourceProvider;
+    let mut workspace = IncludeWorkspace::new(
+        working_dir,
+        BuildOptions::default(),
+        PatchOptions::default(),
+    );
+    for root in roots {
+        workspace
+            .add_root(&working_dir.join(root), &provider)
+            .expect("failed to build include graph");
+    }
+    workspace.patch(&provider);
+    workspace
+}
+
+fn wait_for_events(watcher: &mut WorkspaceWatcher) -> Vec<WatchEvent> {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        let events = watcher.wait(deadline - Instant::now());
+        if !events.is_empty() {
+            return events;
+        }
+    }
+    Vec::new()
+}
+
+#[test]
+fn watch_header_change_updates_including_roots() {
+    let dir = tempfile::tempdir().unwrap();
+    let working_dir = dir.path().canonicalize().unwrap();
+    fs::write(working_dir.join("Common.hlsli"), "float4 common;\n").unwrap();
+    fs::write(working_dir.join("Other.hlsli"), "float4 other;\n").unwrap();
+    fs::write(working_dir.join("A.hlsl"), "#include \"Common.hlsli\"\n").unwrap();
+    fs::write(working_dir.join("B.hlsl"), "#include \"Other.hlsli\"\n").unwrap();
+
+    let workspace = build_workspace(&working_dir, &["A.hlsl", "B.hlsl"]);
+    let old_identity = workspace
+        .root_identity(&working_dir.join("A.hlsl"), &DiskSourceProvider)
+        .map(String::from);
+    let mut watcher = WorkspaceWatcher::new(workspace, DEBOUNCE).unwrap();
+
+    fs::write(working_dir.join("Common.hlsli"), "float4 common_changed;\n").unwrap();
+    let events = wait_for_events(&mut watcher);
+
+    // Only the root including the header changed, and to the identity of a fresh build.
+    let expected = build_workspace(&working_dir, &["A.hlsl", "B.hlsl"]);
+    let new_identity = expected
+        .root_identity(&working_dir.join("A.hlsl"), &DiskSourceProvider)
+        .map(String::from);
+    assert_ne!(old_identity, new_identity);
+    assert_eq!(
+        events,
+        vec![WatchEvent::RootIdentityChanged {
+            root: working_dir.join("A.hlsl"),
+            old: old_identity,
+            new: new_identity,
+        }]
+    );
+}
+
+#[test]
+fn watch_new_include_is_followed() {
+    let dir = tempfile::tempdir().unwrap();
+    let working_dir = dir.path().canonicalize().unwrap();
+    fs::write(working_dir.join("A.hlsl"), "float4 a;\n").unwrap();
+
+    let workspace = build_workspace(&working_dir, &["A.hlsl"]);
+    let mut watcher = WorkspaceWatcher::new(workspace, DEBOUNCE).unwrap();
+
+    fs::write(working_dir.join("New.hlsli"), "float4 new;\n").unwrap();
+    fs::write(working_dir.join("A.hlsl"), "#include \"New.hlsli\"\n").unwrap();
+    let events = wait_for_events(&mut watcher);
+    assert_eq!(events.len(), 1);
+
+    // Later edits to the newly included file are picked up too.
+    fs::write(working_dir.join("New.hlsli"), "float4 new_changed;\n").unwrap();
+    let events = wait_for_events(&mut watcher);
+    assert_eq!(events.len(), 1);
+
+    let expected = build_workspace(&working_dir, &["A.hlsl"]);
+    assert_eq!(
+        watcher
+            .workspace()
+            .root_identity(&working_dir.join("A.hlsl"), &DiskSourceProvider),
+        expected.root_identity(&working_dir.join("A.hlsl"), &DiskSourceProvider)
+    );
+}
@@ -0,0 +1,100 @@
+use include_merkle::*;
+use std::path::{Path, PathBuf};
+
+fn fresh_identity(provider: &MemorySourceProvider, root: &str) -> Result<String, IncludeError> {
+    let mut workspace = IncludeWorkspace::new(
+        Path::new("/shaders"),
+        BuildOptions::default(),
+        PatchOptions::default(),
+    );
+    workspace.add_root(Path::new(root), provider)?;
+    workspace.patch(provider);
+    Ok(workspace
+        .root_identity(Path::new(root), provider)
+        .unwrap()
+        .to_string())
+}
+
+#[test]
+fn rehash_failure_is_retried() {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert("/shaders/A.hlsl", "#include \"B.hlsli\"\n");
+    provider.insert("/shaders/B.hlsli", "float b;\n");
+    provider.insert("/shaders/D.hlsl", "float d;\n");
+    let mut workspace = IncludeWorkspace::new(
+        Path::new("/shaders"),
+        BuildOptions::default(),
+        PatchOptions::default(),
+    );
+    for root in ["/shaders/A.hlsl", "/shaders/D.hlsl"] {
+        workspace
+            .add_root(Path::new(root), &provider)
+            .expect("failed to add root");
+    }
+    workspace.patch(&provider);
+
+    // Introduce a cycle.
+    provider.insert("/shaders/B.hlsli", "#include \"A.hlsl\"\n");
+    assert!(matches!(
+        workspace.rehash_changed(&provider),
+        Err(IncludeError::Cycle { .. })
+    ));
+    assert!(fresh_identity(&provider, "/shaders/A.hlsl").is_err());
+
+    // The failing file is still reported as changed, so an unrelated change doesn't hide it.
+    assert_eq!(
+        workspace.detect_changes(&provider),
+        [PathBuf::from("/shaders/B.hlsli")]
+    );
+    provider.insert("/shaders/D.hlsl", "float d2;\n");
+    assert!(workspace.rehash_changed(&provider).is_err());
+
+    // Fixing the cycle updates both roots.
+    provider.insert("/shaders/B.hlsli", "float b2;\n");
+    let changes = workspace
+        .rehash_changed(&provider)
+        .expect("failed to rehash");
+    assert_eq!(changes.len(), 2);
+    for root in ["/shaders/A.hlsl", "/shaders/D.hlsl"] {
+        assert_eq!(
+            workspace.root_identity(Path::new(root), &provider),
+            Some(fresh_identity(&provider, root).unwrap().as_str())
+        );
+    }
+}
+
+#[test]
+fn rehash_reordered_roots() {
+    let mut provider = MemorySourceProvider::new();
+    provider.insert("/shaders/X.hlsl", "float x;\n");
+    provider.insert("/shaders/A.hlsl", "#include \"B.hlsl\"\n");
+    provider.insert("/shaders/B.hlsl", "float b;\n");
+    let mut workspace = IncludeWorkspace::new(
+        Path::new("/shaders"),
+        BuildOptions::default(),
+        PatchOptions::default(),
+    );
+    for root in ["/shaders/X.hlsl", "/shaders/A.hlsl", "/shaders/B.hlsl"] {
+        workspace
+            .add_root(Path::new(root), &provider)
+            .expect("failed to add root");
+    }
+    workspace.patch(&provider);
+    let old = workspace
+        .root_identity(Path::new("/shaders/X.hlsl"), &provider)
+        .map(str::to_string);
+
+    // Rebuilding the graph visits `B.hlsl` from `X.hlsl`, before `A.hlsl`.
+    provider.insert("/shaders/X.hlsl", "#include \"B.hlsl\"\nfloat x;\n");
+    let changes = workspace
+        .rehash_changed(&provider)
+        .expect("failed to rehash");
+    assert_eq!(
+        changes,
+        [RootIdentityChange {
+            root: PathBuf::from("/shaders/X.hlsl"),
+            old,
+            new: Some(fresh_identity(&provider, "/shaders/X.hlsl").unwrap()),
+        }]
+    );
+}